@@ -0,0 +1,462 @@
+use std::f64::consts::PI;
+
+use crate::hittable::HitRecord;
+use crate::integrator::{
+    Emission, Integrator, Radiance, escaped_radiance, russian_roulette, sample_emission,
+    sample_light_sources,
+};
+use crate::ray::{Ray, RayKind};
+use crate::world::World;
+
+use glam::DVec3;
+
+/// Bidirectional path tracer. A subpath is traced from the camera and one from a randomly
+/// picked emitter, and every prefix of the one is connected to every prefix of the other.
+/// The strategies are combined with the balance heuristic.
+///
+/// Connections straight to the camera (light tracing) are not made, so caustics seen
+/// directly through a specular surface still rely on the camera subpath hitting the emitter.
+///
+/// Emitters are picked uniformly rather than through the light BVH, as the light subpath
+/// has to start without knowing which camera vertex it will be connected to.
+pub struct Bidirectional {
+    pub max_depth: u32,
+    pub rr_min_depth: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum VertexKind {
+    Camera,
+    Light,
+    Surface,
+}
+
+struct Vertex<'a> {
+    kind: VertexKind,
+    pos: DVec3,
+    // none for the camera
+    hit_record: Option<HitRecord<'a>>,
+    // throughput of the subpath up to this vertex
+    beta: DVec3,
+    // area densities of sampling this vertex from the start of its own subpath, and from
+    // the other end of the path
+    pdf_fwd: f64,
+    pdf_rev: f64,
+    // scattered into a discrete direction
+    delta: bool,
+}
+
+impl<'a> Vertex<'a> {
+    fn camera(pos: DVec3) -> Self {
+        Self {
+            kind: VertexKind::Camera,
+            pos,
+            hit_record: None,
+            beta: DVec3::ONE,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            delta: false,
+        }
+    }
+
+    fn light(hit_record: HitRecord<'a>, beta: DVec3, pdf_fwd: f64) -> Self {
+        Self {
+            kind: VertexKind::Light,
+            pos: hit_record.pos,
+            hit_record: Some(hit_record),
+            beta,
+            pdf_fwd,
+            pdf_rev: 0.0,
+            delta: false,
+        }
+    }
+
+    fn surface(hit_record: HitRecord<'a>, beta: DVec3) -> Self {
+        Self {
+            kind: VertexKind::Surface,
+            pos: hit_record.pos,
+            hit_record: Some(hit_record),
+            beta,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            delta: false,
+        }
+    }
+
+    fn normal(&self) -> Option<DVec3> {
+        self.hit_record.as_ref().map(|hit_record| hit_record.normal)
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.hit_record
+            .as_ref()
+            .is_some_and(|hit_record| hit_record.material.is_emissive())
+    }
+
+    fn is_connectible(&self) -> bool {
+        match self.kind {
+            VertexKind::Camera => false,
+            VertexKind::Light => true,
+            VertexKind::Surface => self
+                .hit_record
+                .as_ref()
+                .is_some_and(|hit_record| !hit_record.material.is_specular()),
+        }
+    }
+
+    /// Turns a solid angle density of sampling `next` from here into an area density.
+    fn convert_density(&self, pdf: f64, next: &Vertex) -> f64 {
+        let to_next = next.pos - self.pos;
+        let dist_squared = to_next.length_squared();
+        if dist_squared == 0.0 {
+            return 0.0;
+        }
+        match next.normal() {
+            Some(normal) => pdf * DVec3::dot(normal, to_next).abs() / dist_squared.powf(1.5),
+            None => pdf / dist_squared,
+        }
+    }
+
+    /// Radiance emitted from this vertex towards `next`.
+    fn le(&self, world: &World, next: &Vertex) -> DVec3 {
+        match &self.hit_record {
+            Some(hit_record) => {
+                let receiver = next.hit_record.as_ref().map(|hit_record| hit_record.object);
+                world.emitted(hit_record, (next.pos - self.pos).normalize(), receiver)
+            }
+            None => DVec3::ZERO,
+        }
+    }
+
+    /// BSDF for light going from `prev` over this vertex to `next`.
+    fn f(&self, prev: &Vertex, next: &Vertex) -> DVec3 {
+        let Some(hit_record) = &self.hit_record else {
+            return DVec3::ZERO;
+        };
+        let wo = (prev.pos - self.pos).normalize();
+        let wi = (next.pos - self.pos).normalize();
+        hit_record.material.bsdf(hit_record, wo, wi)
+    }
+
+    /// Area density of this vertex sampling `next` when reached from `prev`.
+    fn pdf(&self, prev: Option<&Vertex>, next: &Vertex) -> f64 {
+        if self.kind == VertexKind::Light {
+            return self.pdf_light(next);
+        }
+        let (Some(hit_record), Some(prev)) = (&self.hit_record, prev) else {
+            return 0.0;
+        };
+        let wo = (prev.pos - self.pos).normalize();
+        let wi = (next.pos - self.pos).normalize();
+        self.convert_density(hit_record.material.pdf(hit_record, wo, wi), next)
+    }
+
+    /// Area density of an emitter at this vertex sending light towards `next`.
+    fn pdf_light(&self, next: &Vertex) -> f64 {
+        let Some(normal) = self.normal() else {
+            return 0.0;
+        };
+        let dir = (next.pos - self.pos).normalize();
+        // cosine weighted on a randomly picked side
+        let pdf = DVec3::dot(normal, dir).abs() / PI / 2.0;
+        self.convert_density(pdf, next)
+    }
+
+    /// Area density of `World::sample_light` picking this vertex.
+    fn pdf_light_origin(&self, world: &World) -> f64 {
+        match &self.hit_record {
+            Some(hit_record) => world.light_pdf(hit_record.object),
+            None => 0.0,
+        }
+    }
+}
+
+impl Bidirectional {
+    fn camera_subpath<'a>(
+        &self,
+        ray: &Ray,
+        world: &World<'a>,
+        path: &mut Vec<Vertex<'a>>,
+    ) -> Radiance {
+        path.push(Vertex::camera(ray.origin));
+        // the camera density cancels out since light tracing strategies are not used
+        self.random_walk(
+            world,
+            *ray,
+            DVec3::ONE,
+            1.0,
+            path,
+            self.max_depth as usize + 2,
+        )
+    }
+
+    fn light_subpath<'a>(&self, world: &World<'a>, path: &mut Vec<Vertex<'a>>) {
+        let Some(Emission {
+            hit_record,
+            ray,
+            le,
+            cosine,
+            pdf_pos,
+            pdf_dir,
+        }) = sample_emission(world)
+        else {
+            return;
+        };
+        let beta = le * cosine / (pdf_pos * pdf_dir);
+        path.push(Vertex::light(hit_record, le / pdf_pos, pdf_pos));
+        self.random_walk(world, ray, beta, pdf_dir, path, self.max_depth as usize + 1);
+
+        // the light doesn't reach the first surface if they aren't linked, and neither does
+        // any other strategy building the same paths
+        let group = path[0].hit_record.as_ref().unwrap().material.light_group();
+        let receiver = path.get(1).and_then(|vertex| vertex.hit_record.as_ref());
+        if receiver.is_some_and(|receiver| !World::illuminates(group, Some(receiver.object))) {
+            path.truncate(1);
+        }
+    }
+
+    /// Extends `path` along `ray`. Returns the background radiance if the path escapes.
+    fn random_walk<'a>(
+        &self,
+        world: &World<'a>,
+        mut ray: Ray,
+        mut beta: DVec3,
+        mut pdf_dir: f64,
+        path: &mut Vec<Vertex<'a>>,
+        max_vertices: usize,
+    ) -> Radiance {
+        while path.len() < max_vertices {
+            let last = path.last().unwrap();
+            let kind = match last.kind {
+                VertexKind::Camera => RayKind::Camera,
+                _ if last.delta => RayKind::Specular,
+                _ => RayKind::Diffuse,
+            };
+            let Some(hit_record) = world.trace(&ray, kind) else {
+                // the ray was sampled by the last vertex unless that's the camera
+                let bsdf_pdf = (path.len() > 1 && !last.delta).then_some(pdf_dir);
+                let receiver = last.hit_record.as_ref().map(|hit_record| hit_record.object);
+                return beta * escaped_radiance(world, &ray, receiver, bsdf_pdf);
+            };
+
+            let mut vertex = Vertex::surface(hit_record, beta);
+            vertex.pdf_fwd = path.last().unwrap().convert_density(pdf_dir, &vertex);
+            path.push(vertex);
+            if path.len() >= max_vertices {
+                break;
+            }
+
+            let n = path.len();
+            let hit_record = path[n - 1].hit_record.as_ref().unwrap();
+            let Some((scattered, attenuation)) = hit_record.material.scatter(&ray, hit_record)
+            else {
+                break;
+            };
+
+            let specular = hit_record.material.is_specular();
+            let (pdf_fwd, pdf_rev) = if specular {
+                (0.0, 0.0)
+            } else {
+                let wo = -ray.dir.normalize();
+                let wi = scattered.dir.normalize();
+                (
+                    hit_record.material.pdf(hit_record, wo, wi),
+                    hit_record.material.pdf(hit_record, wi, wo),
+                )
+            };
+
+            beta *= attenuation;
+            if n > self.rr_min_depth as usize && !russian_roulette(&mut beta) {
+                break;
+            }
+
+            path[n - 1].delta = specular;
+            path[n - 2].pdf_rev = path[n - 1].convert_density(pdf_rev, &path[n - 2]);
+            pdf_dir = pdf_fwd;
+            ray = scattered;
+        }
+        Radiance::default()
+    }
+
+    /// Contribution of the path made of the first `s` light and `t` camera vertices.
+    fn connect(
+        &self,
+        world: &World,
+        light: &[Vertex],
+        camera: &[Vertex],
+        s: usize,
+        t: usize,
+    ) -> Radiance {
+        let pt = &camera[t - 1];
+        // the light group of the emitter the path starts at
+        let group = |vertex: &Vertex| {
+            let hit_record = vertex.hit_record.as_ref().unwrap();
+            world.light_group_index(hit_record.material.light_group())
+        };
+
+        let mut sampled = None;
+        let (l, group) = if s == 0 {
+            if !pt.is_emissive() {
+                return Radiance::default();
+            }
+            (pt.beta * pt.le(world, &camera[t - 2]), group(pt))
+        } else if s == 1 {
+            if !pt.is_connectible() {
+                return Radiance::default();
+            }
+            let Some((hit_record, pdf_pos)) = world.sample_light() else {
+                return Radiance::default();
+            };
+            let receiver = pt.hit_record.as_ref().map(|hit_record| hit_record.object);
+            let le = world.emitted(&hit_record, (pt.pos - hit_record.pos).normalize(), receiver);
+            let vertex = Vertex::light(hit_record, le / pdf_pos, pdf_pos);
+            let l = pt.beta * pt.f(&camera[t - 2], &vertex) * vertex.beta;
+            let l = if l == DVec3::ZERO {
+                l
+            } else {
+                l * Self::g(world, pt, &vertex)
+            };
+            let group = group(&vertex);
+            sampled = Some(vertex);
+            (l, group)
+        } else {
+            let qs = &light[s - 1];
+            if !qs.is_connectible() || !pt.is_connectible() {
+                return Radiance::default();
+            }
+            let l = qs.beta * qs.f(&light[s - 2], pt) * pt.f(&camera[t - 2], qs) * pt.beta;
+            let l = if l == DVec3::ZERO {
+                l
+            } else {
+                l * Self::g(world, qs, pt)
+            };
+            (l, group(&light[0]))
+        };
+
+        if l == DVec3::ZERO {
+            return Radiance::default();
+        }
+        let weight = Self::mis_weight(world, light, camera, sampled.as_ref(), s, t);
+        Radiance::new(group, l * weight)
+    }
+
+    /// Geometry term between two vertices, including visibility.
+    fn g(world: &World, a: &Vertex, b: &Vertex) -> f64 {
+        if !world.visible(a.pos, b.pos) {
+            return 0.0;
+        }
+        let d = b.pos - a.pos;
+        let dist_squared = d.length_squared();
+        let dir = d / dist_squared.sqrt();
+        let mut g = 1.0 / dist_squared;
+        if let Some(normal) = a.normal() {
+            g *= DVec3::dot(normal, dir).abs();
+        }
+        if let Some(normal) = b.normal() {
+            g *= DVec3::dot(normal, dir).abs();
+        }
+        g
+    }
+
+    /// Balance heuristic weight of the (`s`, `t`) strategy among all the ones that could
+    /// have made the same path, computed from the ratios of the vertex densities.
+    fn mis_weight(
+        world: &World,
+        light: &[Vertex],
+        camera: &[Vertex],
+        sampled: Option<&Vertex>,
+        s: usize,
+        t: usize,
+    ) -> f64 {
+        if s + t == 2 {
+            return 1.0;
+        }
+
+        let qs = match s {
+            0 => None,
+            1 => sampled,
+            _ => Some(&light[s - 1]),
+        };
+        let pt = &camera[t - 1];
+        let pt_minus = &camera[t - 2];
+
+        // reverse densities at the connection, as if the path had been sampled the other way
+        let pt_rev = match qs {
+            Some(qs) => qs.pdf(s.checked_sub(2).map(|i| &light[i]), pt),
+            None => pt.pdf_light_origin(world),
+        };
+        let pt_minus_rev = match qs {
+            Some(qs) => pt.pdf(Some(qs), pt_minus),
+            None => pt.pdf_light(pt_minus),
+        };
+        let qs_rev = qs.map_or(0.0, |qs| pt.pdf(Some(pt_minus), qs));
+        let qs_minus_rev = match (qs, s) {
+            (Some(qs), 2..) => qs.pdf(Some(pt), &light[s - 2]),
+            _ => 0.0,
+        };
+
+        let remap = |pdf: f64| if pdf == 0.0 { 1.0 } else { pdf };
+        let mut sum = 0.0;
+
+        let mut ratio = 1.0;
+        for i in (1..t).rev() {
+            let pdf_rev = match i {
+                _ if i == t - 1 => pt_rev,
+                _ if i == t - 2 => pt_minus_rev,
+                _ => camera[i].pdf_rev,
+            };
+            ratio *= remap(pdf_rev) / remap(camera[i].pdf_fwd);
+            let delta = i != t - 1 && camera[i].delta;
+            // i == 1 would connect straight to the camera, which isn't done
+            if i > 1 && !delta && !camera[i - 1].delta {
+                sum += ratio;
+            }
+        }
+
+        let mut ratio = 1.0;
+        for i in (0..s).rev() {
+            let (vertex, pdf_rev) = match i {
+                _ if i == s - 1 => (qs.unwrap(), qs_rev),
+                _ if i == s - 2 => (&light[i], qs_minus_rev),
+                _ => (&light[i], light[i].pdf_rev),
+            };
+            ratio *= remap(pdf_rev) / remap(vertex.pdf_fwd);
+            let delta = i != s - 1 && vertex.delta;
+            let prev_delta = i > 0 && light[i - 1].delta;
+            if !delta && !prev_delta {
+                sum += ratio;
+            }
+        }
+
+        1.0 / (1.0 + sum)
+    }
+}
+
+impl Integrator for Bidirectional {
+    fn li(&self, ray: &Ray, world: &World) -> Radiance {
+        let mut camera = Vec::with_capacity(self.max_depth as usize + 2);
+        let mut light = Vec::with_capacity(self.max_depth as usize + 1);
+        // only the camera subpath can find the background
+        let mut color = self.camera_subpath(ray, world, &mut camera);
+        self.light_subpath(world, &mut light);
+
+        for t in 2..=camera.len() {
+            for s in 0..=light.len() {
+                if s + t - 2 > self.max_depth as usize {
+                    continue;
+                }
+                color += self.connect(world, &light, &camera, s, t);
+            }
+
+            // light sources can only be reached by sampling them, so there is nothing to
+            // weight this strategy against
+            if t - 1 <= self.max_depth as usize {
+                let pt = &camera[t - 1];
+                let wo = (camera[t - 2].pos - pt.pos).normalize();
+                color += pt.beta
+                    * sample_light_sources(world, pt.hit_record.as_ref().unwrap(), wo, true);
+            }
+        }
+        color
+    }
+}
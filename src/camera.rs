@@ -1,11 +1,34 @@
+#![allow(dead_code)]
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::bdpt::Bidirectional;
+use crate::checkpoint::{Checkpoint, RenderSettings};
+use crate::debug::DebugIntegrator;
+use crate::film::{Film, Pixel};
+use crate::filter::Filter;
 use crate::hittable::Hittable;
-use crate::ray::Ray;
+use crate::integrator::{
+    Integrator, IntegratorKind, PathTracer, Radiance, camera_alpha, camera_sample,
+};
+use crate::light::LightSource;
+use crate::metropolis::Metropolis;
+use crate::photon_map::PhotonMapper;
+use crate::ray::{Ray, RayDifferential};
+use crate::sampler::Sampler;
 use crate::texture::Texture;
+use crate::world::World;
 
-use fastrand::Rng;
 use glam::DVec3;
 use pbr::ProgressBar;
 
+// fraction of a pixel the ray differentials are offset by, the footprint of a sample as if
+// there were 16 per pixel. It doesn't depend on the actual sample count, so progressive,
+// resumed and adaptive renders filter textures the same way.
+const DIFFERENTIAL_SPACING: f64 = 0.25;
+
 pub struct Camera {
     pub pos: DVec3,
     pub lookat: DVec3,
@@ -16,8 +39,66 @@ pub struct Camera {
     pub aspect_ratio: f64,
     pub fov: f64,
     pub sample_per_pixel: u32,
+    pub integrator: IntegratorKind,
     pub max_depth: u32,
+    // bounces before Russian roulette may end a path
+    pub rr_min_depth: u32,
+    // radiance of rays leaving the scene, on top of any environment light
     pub background: DVec3,
+    // camera rays that leave the scene get zero alpha instead of the background
+    pub transparent: bool,
+    pub seed: u64,
+    pub filter: Filter,
+    // overrides `sample_per_pixel` when set
+    pub adaptive: Option<AdaptiveSampling>,
+    // renders in passes up to `sample_per_pixel` when set
+    pub progressive: Option<Progressive>,
+    // periodically saves the progressive render state when set
+    pub checkpoint: Option<Checkpointing>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveSampling {
+    pub min_samples: u32,
+    pub max_samples: u32,
+    // a pixel is done once its relative standard error falls below this
+    pub target_error: f64,
+}
+
+impl AdaptiveSampling {
+    pub fn is_done(&self, pixel: &Pixel) -> bool {
+        pixel.samples >= self.max_samples
+            || pixel.samples >= self.min_samples && pixel.relative_error() < self.target_error
+    }
+}
+
+impl Default for AdaptiveSampling {
+    fn default() -> Self {
+        AdaptiveSampling {
+            min_samples: 16,
+            max_samples: 1024,
+            target_error: 0.02,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Progressive {
+    pub time_limit: Option<Duration>,
+    // stop once the mean relative error over all pixels falls below this
+    pub noise_threshold: Option<f64>,
+}
+
+pub struct Checkpointing {
+    pub path: PathBuf,
+    pub interval: Duration,
+}
+
+struct Viewport {
+    width: u32,
+    upper_left: DVec3,
+    delta_u: DVec3,
+    delta_v: DVec3,
 }
 
 impl Default for Camera {
@@ -32,15 +113,360 @@ impl Default for Camera {
             aspect_ratio: 4.0 / 3.0,
             fov: 90.0,
             sample_per_pixel: 1,
+            integrator: IntegratorKind::default(),
             max_depth: 20,
+            rr_min_depth: 3,
             background: DVec3::new(0.01, 0.01, 0.01),
+            transparent: false,
+            seed: 0,
+            filter: Filter::default(),
+            adaptive: None,
+            progressive: None,
+            checkpoint: None,
         }
     }
 }
 
 impl Camera {
-    pub fn render(&self, world: &Vec<&dyn Hittable>) -> Texture {
-        // init
+    /// Fails if `progressive` or `checkpoint` is set for an integrator that doesn't support
+    /// them, see `IntegratorKind::supports_progressive`.
+    pub fn render(
+        &self,
+        world: &Vec<&dyn Hittable>,
+        lights: &[&dyn LightSource],
+    ) -> io::Result<Texture> {
+        Ok(self.render_film(world, lights)?.to_texture())
+    }
+
+    /// Like `render`, but keeps the per-pixel statistics, e.g. for `Film::heat_map`, and the
+    /// light group layers.
+    pub fn render_film(
+        &self,
+        world: &Vec<&dyn Hittable>,
+        lights: &[&dyn LightSource],
+    ) -> io::Result<Film> {
+        if self.progressive.is_some() || self.checkpoint.is_some() {
+            return self.render_progressive_film(world, lights, |_, _| {});
+        }
+        Ok(self.render_world(&World::new(world, lights, self.background)))
+    }
+
+    /// Renders the image along with one layer per light group, lit only by the group's
+    /// emitters and light sources, so lights can be rebalanced in compositing. The constant
+    /// background belongs to the default group. The layers are accumulated in the same pass
+    /// as the image and add up to it exactly.
+    pub fn render_light_groups(
+        &self,
+        world: &Vec<&dyn Hittable>,
+        lights: &[&dyn LightSource],
+    ) -> io::Result<(Texture, Vec<(String, Texture)>)> {
+        let film = self.render_film(world, lights)?;
+        Ok((film.to_texture(), film.layers()))
+    }
+
+    fn render_world(&self, world: &World) -> Film {
+        let viewport = self.viewport();
+        let mut film = Film::new(
+            viewport.width,
+            self.height,
+            self.filter,
+            world.light_groups.clone(),
+        );
+
+        if let IntegratorKind::Metropolis {
+            bootstrap,
+            chains,
+            large_step_probability,
+            sigma,
+        } = self.integrator
+        {
+            let metropolis = Metropolis {
+                max_depth: self.max_depth,
+                rr_min_depth: self.rr_min_depth,
+                bootstrap,
+                chains,
+                large_step_probability,
+                sigma,
+                seed: self.seed,
+                transparent: self.transparent,
+            };
+            let mutations = self.sample_per_pixel as u64 * film.pixels().len() as u64;
+            metropolis.render(world, &mut film, mutations, |x, y| {
+                self.get_ray(&viewport, 0, 0, (x - 0.5, y - 0.5))
+            });
+
+            // the splats carry no alpha, it comes from ordinary camera samples without color
+            let sampler = Sampler::new(self.seed);
+            for v in 0..self.height {
+                for u in 0..viewport.width {
+                    for i in 0..self.sample_per_pixel {
+                        sampler.start_sample(u, v, i);
+                        let offset = sampler.pixel_offset();
+                        let ray = self.get_ray(&viewport, u, v, offset);
+                        let alpha = camera_alpha(&ray, world, self.transparent);
+                        film.add_sample(u, v, offset, &Radiance::default(), alpha);
+                    }
+                }
+            }
+            return film;
+        }
+
+        let mut integrator = self.integrator();
+        let sampler = Sampler::new(self.seed);
+        let max_samples = self.max_samples();
+
+        let mut pb = ProgressBar::new(max_samples as u64);
+        pb.show_counter = false;
+        pb.show_speed = false;
+        pb.message("Rendering: ");
+        pb.format("[#>-]");
+
+        for i in 0..max_samples {
+            sampler.start_iteration(i);
+            integrator.start_iteration(world, i);
+            for v in 0..self.height {
+                self.render_row(world, &*integrator, &sampler, &viewport, &mut film, v, i);
+            }
+            pb.inc();
+
+            if self.samples_reached(&film) == max_samples {
+                break;
+            }
+        }
+        pb.finish();
+        film
+    }
+
+    /// Renders in passes of 1, 2, 4, ... samples per pixel until `sample_per_pixel` is reached
+    /// or one of the `progressive` stop conditions is met. With `adaptive` set, pixels that
+    /// are done stop taking samples, up to `max_samples`. `on_pass` receives the samples per
+    /// pixel taken so far and the accumulated image after every pass.
+    pub fn render_progressive<F>(
+        &self,
+        world: &Vec<&dyn Hittable>,
+        lights: &[&dyn LightSource],
+        on_pass: F,
+    ) -> io::Result<Texture>
+    where
+        F: FnMut(u32, &Texture),
+    {
+        Ok(self
+            .render_progressive_film(world, lights, on_pass)?
+            .to_texture())
+    }
+
+    fn render_progressive_film<F>(
+        &self,
+        world: &Vec<&dyn Hittable>,
+        lights: &[&dyn LightSource],
+        on_pass: F,
+    ) -> io::Result<Film>
+    where
+        F: FnMut(u32, &Texture),
+    {
+        let world = World::new(world, lights, self.background);
+        let film = Film::new(
+            self.width(),
+            self.height,
+            self.filter,
+            world.light_groups.clone(),
+        );
+        self.continue_progressive(&world, film, on_pass)
+    }
+
+    /// Continues a progressive render from a checkpoint up to `sample_per_pixel`, or the
+    /// adaptive `max_samples`, which may be higher than the one the checkpoint was started
+    /// with. The result is the same as the one
+    /// of an uninterrupted render, see `render_film`.
+    pub fn resume<F>(
+        &self,
+        world: &Vec<&dyn Hittable>,
+        lights: &[&dyn LightSource],
+        path: &Path,
+        on_pass: F,
+    ) -> io::Result<Film>
+    where
+        F: FnMut(u32, &Texture),
+    {
+        let checkpoint = Checkpoint::load(path)?;
+        if !self.render_settings().is_compatible(&checkpoint.settings) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "checkpoint was rendered with different camera settings",
+            ));
+        }
+        let world = World::new(world, lights, self.background);
+        if checkpoint.film.light_groups != world.light_groups {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "checkpoint was rendered with different light groups",
+            ));
+        }
+        self.continue_progressive(&world, checkpoint.film, on_pass)
+    }
+
+    pub fn width(&self) -> u32 {
+        (self.height as f64 * self.aspect_ratio) as u32
+    }
+
+    pub fn render_settings(&self) -> RenderSettings {
+        RenderSettings {
+            width: self.width(),
+            height: self.height,
+            sample_per_pixel: self.sample_per_pixel,
+            integrator: self.integrator,
+            max_depth: self.max_depth,
+            rr_min_depth: self.rr_min_depth,
+            seed: self.seed,
+            adaptive: self.adaptive,
+            pos: self.pos,
+            lookat: self.lookat,
+            fov: self.fov,
+            up: self.up,
+            world_up: self.world_up,
+            aspect_ratio: self.aspect_ratio,
+            background: self.background,
+            transparent: self.transparent,
+            filter: self.filter,
+        }
+    }
+
+    fn continue_progressive<F>(
+        &self,
+        world: &World,
+        mut film: Film,
+        mut on_pass: F,
+    ) -> io::Result<Film>
+    where
+        F: FnMut(u32, &Texture),
+    {
+        if !self.integrator.supports_progressive() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "integrator doesn't support progressive rendering or checkpoints",
+            ));
+        }
+
+        let default = Progressive::default();
+        let progressive = self.progressive.as_ref().unwrap_or(&default);
+        let deadline = progressive.time_limit.map(|limit| Instant::now() + limit);
+        let mut last_checkpoint = Instant::now();
+
+        let mut integrator = self.integrator();
+        let viewport = self.viewport();
+        let sampler = Sampler::new(self.seed);
+
+        let max_samples = self.max_samples();
+        let mut pb = ProgressBar::new(max_samples as u64);
+        pb.show_counter = false;
+        pb.show_speed = false;
+        pb.message("Rendering: ");
+        pb.format("[#>-]");
+
+        let mut samples = self.samples_reached(&film);
+        pb.set(samples as u64);
+        'passes: while samples < max_samples {
+            // passes end at 1, 3, 7, ... samples no matter where a resumed render starts, so
+            // the stop conditions are checked at the same points as without the interruption
+            let pass_target = ((samples + 2).next_power_of_two() - 1).min(max_samples);
+            let first = samples;
+            for i in first..pass_target {
+                sampler.start_iteration(i);
+                integrator.start_iteration(world, i);
+                for v in 0..self.height {
+                    self.render_row(world, &*integrator, &sampler, &viewport, &mut film, v, i);
+
+                    if let Some(checkpoint) = &self.checkpoint
+                        && last_checkpoint.elapsed() >= checkpoint.interval
+                    {
+                        self.save_checkpoint(&film, &checkpoint.path);
+                        last_checkpoint = Instant::now();
+                    }
+
+                    // a cut-short pass still leaves a valid image, the lower rows just have
+                    // fewer samples
+                    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                        samples = self.samples_reached(&film);
+                        on_pass(samples, &film.to_texture());
+                        break 'passes;
+                    }
+                }
+            }
+            samples = self.samples_reached(&film);
+            pb.set(samples as u64);
+
+            on_pass(samples, &film.to_texture());
+
+            if progressive
+                .noise_threshold
+                .is_some_and(|threshold| film.mean_relative_error() < threshold)
+            {
+                break;
+            }
+        }
+        pb.set(samples as u64);
+        pb.finish();
+
+        if let Some(checkpoint) = &self.checkpoint {
+            self.save_checkpoint(&film, &checkpoint.path);
+        }
+        Ok(film)
+    }
+
+    // takes sample `i` of the pixels in row `v` that are due for it
+    #[allow(clippy::too_many_arguments)]
+    fn render_row(
+        &self,
+        world: &World,
+        integrator: &dyn Integrator,
+        sampler: &Sampler,
+        viewport: &Viewport,
+        film: &mut Film,
+        v: u32,
+        i: u32,
+    ) {
+        for u in 0..viewport.width {
+            // pixels may be ahead after a resumed, cut-short iteration
+            let pixel = film.pixel(u, v);
+            if pixel.samples != i || self.is_done(pixel) {
+                continue;
+            }
+            sampler.start_sample(u, v, i);
+            let offset = sampler.pixel_offset();
+            let ray = self.get_ray(viewport, u, v, offset);
+            let (color, alpha) = camera_sample(integrator, &ray, world, self.transparent);
+            film.add_sample(u, v, offset, &color, alpha);
+        }
+    }
+
+    fn max_samples(&self) -> u32 {
+        self.adaptive
+            .map_or(self.sample_per_pixel, |adaptive| adaptive.max_samples)
+    }
+
+    fn is_done(&self, pixel: &Pixel) -> bool {
+        match self.adaptive {
+            Some(adaptive) => adaptive.is_done(pixel),
+            None => pixel.samples >= self.sample_per_pixel,
+        }
+    }
+
+    // samples of the pixels that aren't done yet, every pixel has at least this many unless
+    // adaptive sampling stopped it earlier
+    fn samples_reached(&self, film: &Film) -> u32 {
+        film.pixels()
+            .iter()
+            .filter(|pixel| !self.is_done(pixel))
+            .map(|pixel| pixel.samples)
+            .min()
+            .unwrap_or(self.max_samples())
+    }
+
+    fn save_checkpoint(&self, film: &Film, path: &Path) {
+        Checkpoint::save(path, &self.render_settings(), film).expect("Unable to write checkpoint");
+    }
+
+    fn viewport(&self) -> Viewport {
         let view_dir = self.lookat - self.pos;
         let focal_length = view_dir.length();
 
@@ -56,7 +482,7 @@ impl Camera {
             }
         };
 
-        let width = (self.height as f64 * self.aspect_ratio) as u32;
+        let width = self.width();
 
         let viewport_height = (self.fov / 2.0).to_radians().tan() * focal_length * 2.0;
         let viewport_width = viewport_height * width as f64 / self.height as f64;
@@ -64,49 +490,67 @@ impl Camera {
 
         let delta_u = -left * pixel_size;
         let delta_v = -up * pixel_size;
-        let viewport_upper_left = view_dir
+        let upper_left = view_dir
             + (left * (viewport_width / 2.0))
             + (up * (viewport_height / 2.0))
             + delta_u / 2.0
             + delta_v / 2.0;
 
-        let mut data: Texture = Texture::new(width, self.height);
-
-        let mut rng = Rng::new();
-        let mut offsets: Vec<DVec3> = Vec::with_capacity(self.sample_per_pixel as usize);
-        for _ in 0..self.sample_per_pixel {
-            let offset = delta_u * (rng.f64() - 0.5) + delta_v * (rng.f64() - 0.5);
-            offsets.push(offset);
+        Viewport {
+            width,
+            upper_left,
+            delta_u,
+            delta_v,
         }
+    }
 
-        let mut pb = ProgressBar::new(self.height as u64);
-        pb.show_counter = false;
-        pb.show_speed = false;
-        pb.message("Rendering: ");
-        pb.format("[#>-]");
+    fn integrator(&self) -> Box<dyn Integrator> {
+        match self.integrator {
+            IntegratorKind::PathTracer => Box::new(PathTracer {
+                max_depth: self.max_depth,
+                rr_min_depth: self.rr_min_depth,
+                override_material: None,
+            }),
+            IntegratorKind::Metropolis { .. } => {
+                unreachable!("Metropolis renders the whole film in `render_world`")
+            }
+            IntegratorKind::Bidirectional => Box::new(Bidirectional {
+                max_depth: self.max_depth,
+                rr_min_depth: self.rr_min_depth,
+            }),
+            IntegratorKind::PhotonMapping {
+                photons,
+                radius,
+                alpha,
+            } => Box::new(PhotonMapper::new(
+                self.max_depth,
+                self.rr_min_depth,
+                photons,
+                radius,
+                alpha,
+            )),
+            IntegratorKind::Debug(mode) => Box::new(DebugIntegrator::new(
+                mode,
+                self.max_depth,
+                self.rr_min_depth,
+            )),
+        }
+    }
 
-        let mut view_ray = Ray {
+    // `offset` is the position inside the pixel, in [-0.5, 0.5)
+    fn get_ray(&self, viewport: &Viewport, u: u32, v: u32, offset: (f64, f64)) -> Ray {
+        let dir = viewport.upper_left
+            + viewport.delta_u * (u as f64 + offset.0)
+            + viewport.delta_v * (v as f64 + offset.1);
+        Ray {
             origin: self.pos,
-            dir: viewport_upper_left,
-        };
-        for v in 0..self.height {
-            view_ray.dir = viewport_upper_left + delta_v * v as f64;
-
-            for u in 0..width {
-                let color = offsets.iter().fold(DVec3::ZERO, |acc, offset| {
-                    let sample_ray = Ray {
-                        origin: self.pos,
-                        dir: view_ray.dir + *offset,
-                    };
-                    acc + sample_ray.trace(self.max_depth, world, self.background)
-                }) / self.sample_per_pixel as f64;
-                data.set(u, v, color);
-
-                view_ray.dir += delta_u;
-            }
-            pb.inc();
+            dir,
+            differential: Some(RayDifferential {
+                rx_origin: self.pos,
+                rx_dir: dir + viewport.delta_u * DIFFERENTIAL_SPACING,
+                ry_origin: self.pos,
+                ry_dir: dir + viewport.delta_v * DIFFERENTIAL_SPACING,
+            }),
         }
-        pb.finish();
-        data
     }
 }
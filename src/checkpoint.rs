@@ -0,0 +1,356 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::camera::AdaptiveSampling;
+use crate::debug::DebugMode;
+use crate::film::{Film, Pixel};
+use crate::filter::Filter;
+use crate::integrator::IntegratorKind;
+
+use glam::DVec3;
+
+const MAGIC: &[u8; 4] = b"MRCK";
+const VERSION: u32 = 11;
+
+/// The camera settings a checkpoint was rendered with. The scene itself is not stored, it has
+/// to be the same when resuming.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderSettings {
+    pub width: u32,
+    pub height: u32,
+    pub sample_per_pixel: u32,
+    pub integrator: IntegratorKind,
+    pub max_depth: u32,
+    pub rr_min_depth: u32,
+    pub seed: u64,
+    pub adaptive: Option<AdaptiveSampling>,
+    pub pos: DVec3,
+    pub lookat: DVec3,
+    pub fov: f64,
+    pub up: Option<DVec3>,
+    pub world_up: DVec3,
+    pub aspect_ratio: f64,
+    pub background: DVec3,
+    pub transparent: bool,
+    pub filter: Filter,
+}
+
+impl RenderSettings {
+    /// Whether a render with `self` can continue from a checkpoint with `other`. Only the
+    /// sample count may differ, `sample_per_pixel` or the adaptive `max_samples`.
+    pub fn is_compatible(&self, other: &RenderSettings) -> bool {
+        let adaptive = match (self.adaptive, other.adaptive) {
+            (Some(adaptive), Some(other)) => Some(AdaptiveSampling {
+                max_samples: other.max_samples,
+                ..adaptive
+            }),
+            (adaptive, _) => adaptive,
+        };
+        RenderSettings {
+            sample_per_pixel: other.sample_per_pixel,
+            adaptive,
+            ..self.clone()
+        } == *other
+    }
+}
+
+pub struct Checkpoint {
+    pub settings: RenderSettings,
+    pub film: Film,
+}
+
+impl Checkpoint {
+    pub fn save(path: &Path, settings: &RenderSettings, film: &Film) -> io::Result<()> {
+        // write to a temporary file first so a crash while saving keeps the previous checkpoint
+        let tmp_path = path.with_extension("tmp");
+        let mut w = BufWriter::new(File::create(&tmp_path)?);
+
+        w.write_all(MAGIC)?;
+        write_u32(&mut w, VERSION)?;
+
+        let s = settings;
+        write_u32(&mut w, s.width)?;
+        write_u32(&mut w, s.height)?;
+        write_u32(&mut w, s.sample_per_pixel)?;
+        write_integrator(&mut w, s.integrator)?;
+        write_u32(&mut w, s.max_depth)?;
+        write_u32(&mut w, s.rr_min_depth)?;
+        w.write_all(&s.seed.to_le_bytes())?;
+        write_adaptive(&mut w, s.adaptive)?;
+        write_dvec3(&mut w, s.pos)?;
+        write_dvec3(&mut w, s.lookat)?;
+        write_f64(&mut w, s.fov)?;
+        write_option_dvec3(&mut w, s.up)?;
+        write_dvec3(&mut w, s.world_up)?;
+        write_f64(&mut w, s.aspect_ratio)?;
+        write_dvec3(&mut w, s.background)?;
+        write_u32(&mut w, s.transparent as u32)?;
+        write_filter(&mut w, s.filter)?;
+
+        write_u32(&mut w, film.light_groups.len() as u32)?;
+        for group in &film.light_groups {
+            write_string(&mut w, group)?;
+        }
+
+        for pixel in film.pixels() {
+            write_dvec3(&mut w, pixel.sum)?;
+            write_f64(&mut w, pixel.weight_sum)?;
+            write_f64(&mut w, pixel.alpha_sum)?;
+            write_f64(&mut w, pixel.lum_sum)?;
+            write_f64(&mut w, pixel.lum_sq_sum)?;
+            write_u32(&mut w, pixel.samples)?;
+        }
+        for sum in film.layer_sums() {
+            write_dvec3(&mut w, *sum)?;
+        }
+
+        w.into_inner()?.sync_all()?;
+        std::fs::rename(tmp_path, path)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut r = BufReader::new(File::open(path)?);
+
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC || read_u32(&mut r)? != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a miniray checkpoint",
+            ));
+        }
+
+        let settings = RenderSettings {
+            width: read_u32(&mut r)?,
+            height: read_u32(&mut r)?,
+            sample_per_pixel: read_u32(&mut r)?,
+            integrator: read_integrator(&mut r)?,
+            max_depth: read_u32(&mut r)?,
+            rr_min_depth: read_u32(&mut r)?,
+            seed: u64::from_le_bytes(read_array(&mut r)?),
+            adaptive: read_adaptive(&mut r)?,
+            pos: read_dvec3(&mut r)?,
+            lookat: read_dvec3(&mut r)?,
+            fov: read_f64(&mut r)?,
+            up: read_option_dvec3(&mut r)?,
+            world_up: read_dvec3(&mut r)?,
+            aspect_ratio: read_f64(&mut r)?,
+            background: read_dvec3(&mut r)?,
+            transparent: read_u32(&mut r)? != 0,
+            filter: read_filter(&mut r)?,
+        };
+
+        let light_groups = (0..read_u32(&mut r)?)
+            .map(|_| read_string(&mut r))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let mut pixels = Vec::with_capacity((settings.width * settings.height) as usize);
+        for _ in 0..settings.width * settings.height {
+            pixels.push(Pixel {
+                sum: read_dvec3(&mut r)?,
+                weight_sum: read_f64(&mut r)?,
+                alpha_sum: read_f64(&mut r)?,
+                lum_sum: read_f64(&mut r)?,
+                lum_sq_sum: read_f64(&mut r)?,
+                samples: read_u32(&mut r)?,
+            });
+        }
+        let layers = (0..pixels.len() * light_groups.len())
+            .map(|_| read_dvec3(&mut r))
+            .collect::<io::Result<Vec<_>>>()?;
+        let film = Film::from_pixels(
+            settings.width,
+            settings.height,
+            settings.filter,
+            light_groups,
+            pixels,
+            layers,
+        );
+
+        Ok(Self { settings, film })
+    }
+}
+
+fn write_u32(w: &mut impl Write, value: u32) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+fn write_f64(w: &mut impl Write, value: f64) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+fn write_string(w: &mut impl Write, value: &str) -> io::Result<()> {
+    write_u32(w, value.len() as u32)?;
+    w.write_all(value.as_bytes())
+}
+
+fn write_dvec3(w: &mut impl Write, value: DVec3) -> io::Result<()> {
+    value.to_array().iter().try_for_each(|x| write_f64(w, *x))
+}
+
+// a flag followed by the value, zero if there is none
+fn write_option_dvec3(w: &mut impl Write, value: Option<DVec3>) -> io::Result<()> {
+    write_u32(w, value.is_some() as u32)?;
+    write_dvec3(w, value.unwrap_or(DVec3::ZERO))
+}
+
+fn write_adaptive(w: &mut impl Write, adaptive: Option<AdaptiveSampling>) -> io::Result<()> {
+    write_u32(w, adaptive.is_some() as u32)?;
+    let adaptive = adaptive.unwrap_or(AdaptiveSampling {
+        min_samples: 0,
+        max_samples: 0,
+        target_error: 0.0,
+    });
+    write_u32(w, adaptive.min_samples)?;
+    write_u32(w, adaptive.max_samples)?;
+    write_f64(w, adaptive.target_error)
+}
+
+// a tag followed by up to three parameters, unused ones are zero
+fn write_filter(w: &mut impl Write, filter: Filter) -> io::Result<()> {
+    let (tag, params) = match filter {
+        Filter::Box { radius } => (0, [radius, 0.0, 0.0]),
+        Filter::Tent { radius } => (1, [radius, 0.0, 0.0]),
+        Filter::Gaussian { radius, sigma } => (2, [radius, sigma, 0.0]),
+        Filter::Mitchell { radius, b, c } => (3, [radius, b, c]),
+        Filter::Lanczos { radius } => (4, [radius, 0.0, 0.0]),
+        Filter::BlackmanHarris { radius } => (5, [radius, 0.0, 0.0]),
+    };
+    write_u32(w, tag)?;
+    params.iter().try_for_each(|x| write_f64(w, *x))
+}
+
+fn write_integrator(w: &mut impl Write, integrator: IntegratorKind) -> io::Result<()> {
+    let (tag, params) = match integrator {
+        IntegratorKind::PathTracer => (0, [0.0; 4]),
+        IntegratorKind::Bidirectional => (1, [0.0; 4]),
+        IntegratorKind::PhotonMapping {
+            photons,
+            radius,
+            alpha,
+        } => (2, [photons as f64, radius, alpha, 0.0]),
+        IntegratorKind::Metropolis { .. } => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Metropolis renders can't be checkpointed",
+            ));
+        }
+        IntegratorKind::Debug(mode) => {
+            let (mode, param) = match mode {
+                DebugMode::ShadingNormal => (0, 0.0),
+                DebugMode::GeometricNormal => (1, 0.0),
+                DebugMode::Uv => (2, 0.0),
+                DebugMode::Barycentric => (3, 0.0),
+                DebugMode::Facing => (4, 0.0),
+                DebugMode::MaterialId => (5, 0.0),
+                DebugMode::Depth { max_distance } => (6, max_distance),
+                DebugMode::AmbientOcclusion { distance } => (7, distance),
+                DebugMode::WhiteClay => (8, 0.0),
+            };
+            (4, [mode as f64, param, 0.0, 0.0])
+        }
+    };
+    write_u32(w, tag)?;
+    params.iter().try_for_each(|x| write_f64(w, *x))
+}
+
+fn read_integrator(r: &mut impl Read) -> io::Result<IntegratorKind> {
+    let tag = read_u32(r)?;
+    let [p0, p1, p2, _] = [read_f64(r)?, read_f64(r)?, read_f64(r)?, read_f64(r)?];
+    Ok(match tag {
+        0 => IntegratorKind::PathTracer,
+        1 => IntegratorKind::Bidirectional,
+        2 => IntegratorKind::PhotonMapping {
+            photons: p0 as u32,
+            radius: p1,
+            alpha: p2,
+        },
+        4 => IntegratorKind::Debug(match p0 as u32 {
+            0 => DebugMode::ShadingNormal,
+            1 => DebugMode::GeometricNormal,
+            2 => DebugMode::Uv,
+            3 => DebugMode::Barycentric,
+            4 => DebugMode::Facing,
+            5 => DebugMode::MaterialId,
+            6 => DebugMode::Depth { max_distance: p1 },
+            7 => DebugMode::AmbientOcclusion { distance: p1 },
+            8 => DebugMode::WhiteClay,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unknown debug mode in checkpoint",
+                ));
+            }
+        }),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unknown integrator in checkpoint",
+            ));
+        }
+    })
+}
+
+fn read_array<const N: usize>(r: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    Ok(u32::from_le_bytes(read_array(r)?))
+}
+
+fn read_f64(r: &mut impl Read) -> io::Result<f64> {
+    Ok(f64::from_le_bytes(read_array(r)?))
+}
+
+fn read_filter(r: &mut impl Read) -> io::Result<Filter> {
+    let tag = read_u32(r)?;
+    let [radius, p1, p2] = [read_f64(r)?, read_f64(r)?, read_f64(r)?];
+    Ok(match tag {
+        0 => Filter::Box { radius },
+        1 => Filter::Tent { radius },
+        2 => Filter::Gaussian { radius, sigma: p1 },
+        3 => Filter::Mitchell {
+            radius,
+            b: p1,
+            c: p2,
+        },
+        4 => Filter::Lanczos { radius },
+        5 => Filter::BlackmanHarris { radius },
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unknown filter in checkpoint",
+            ));
+        }
+    })
+}
+
+fn read_dvec3(r: &mut impl Read) -> io::Result<DVec3> {
+    Ok(DVec3::new(read_f64(r)?, read_f64(r)?, read_f64(r)?))
+}
+
+fn read_option_dvec3(r: &mut impl Read) -> io::Result<Option<DVec3>> {
+    let is_some = read_u32(r)? != 0;
+    let value = read_dvec3(r)?;
+    Ok(is_some.then_some(value))
+}
+
+fn read_adaptive(r: &mut impl Read) -> io::Result<Option<AdaptiveSampling>> {
+    let is_some = read_u32(r)? != 0;
+    let adaptive = AdaptiveSampling {
+        min_samples: read_u32(r)?,
+        max_samples: read_u32(r)?,
+        target_error: read_f64(r)?,
+    };
+    Ok(is_some.then_some(adaptive))
+}
+
+fn read_string(r: &mut impl Read) -> io::Result<String> {
+    let mut bytes = vec![0; read_u32(r)? as usize];
+    r.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
@@ -0,0 +1,105 @@
+use crate::glam_ext::DVec3Ext;
+use crate::hittable::Facing;
+use crate::integrator::{Integrator, PathTracer, Radiance};
+use crate::material::{Lambertian, Material};
+use crate::ray::{Ray, RayKind};
+use crate::world::World;
+
+use glam::DVec3;
+
+/// What `DebugIntegrator` shows. Everything but `WhiteClay` only looks at the first hit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DebugMode {
+    ShadingNormal,
+    GeometricNormal,
+    Uv,
+    Barycentric,
+    // green for front faces, red for back faces
+    Facing,
+    // a random color per material
+    MaterialId,
+    // white at the camera, black at `max_distance` and beyond
+    Depth { max_distance: f64 },
+    // the fraction of the hemisphere not occluded within `distance`
+    AmbientOcclusion { distance: f64 },
+    // path traced with every material replaced by a neutral gray, emitters aside
+    WhiteClay,
+}
+
+pub struct DebugIntegrator {
+    pub mode: DebugMode,
+    clay: PathTracer,
+}
+
+impl DebugIntegrator {
+    pub fn new(mode: DebugMode, max_depth: u32, rr_min_depth: u32) -> Self {
+        Self {
+            mode,
+            clay: PathTracer {
+                max_depth,
+                rr_min_depth,
+                override_material: Some(Lambertian::new(DVec3::splat(0.8))),
+            },
+        }
+    }
+}
+
+// maps a unit vector to a color
+fn direction_color(dir: DVec3) -> DVec3 {
+    dir * 0.5 + 0.5
+}
+
+impl Integrator for DebugIntegrator {
+    fn li(&self, ray: &Ray, world: &World) -> Radiance {
+        if self.mode == DebugMode::WhiteClay {
+            return self.clay.li(ray, world);
+        }
+
+        let Some(x) = world.trace(ray, RayKind::Camera) else {
+            return Radiance::default();
+        };
+
+        let color = match self.mode {
+            DebugMode::ShadingNormal => direction_color(x.normal),
+            DebugMode::GeometricNormal => direction_color(x.geometric_normal),
+            DebugMode::Uv => x.tex_coords.extend(0.0),
+            DebugMode::Barycentric => DVec3::new(
+                1.0 - x.barycentric.x - x.barycentric.y,
+                x.barycentric.x,
+                x.barycentric.y,
+            ),
+            DebugMode::Facing => match x.facing {
+                Facing::Front => DVec3::Y,
+                Facing::Back => DVec3::X,
+            },
+            DebugMode::MaterialId => {
+                // the material's address is as good an ID as any
+                let address = x.material as *const dyn Material as *const () as u64;
+                let mut rng = fastrand::Rng::with_seed(address);
+                DVec3::new(rng.f64(), rng.f64(), rng.f64())
+            }
+            DebugMode::Depth { max_distance } => {
+                let distance = x.t * ray.dir.length();
+                DVec3::splat((1.0 - distance / max_distance).max(0.0))
+            }
+            DebugMode::AmbientOcclusion { distance } => {
+                let normal = x.facing_normal();
+                let mut dir = normal + DVec3::random();
+                if dir.near_zero() {
+                    dir = normal;
+                }
+                let occluder = Ray {
+                    origin: x.pos,
+                    dir: dir.normalize(),
+                    differential: None,
+                };
+                let occluded = world
+                    .trace(&occluder, RayKind::Shadow)
+                    .is_some_and(|hit| hit.t < distance);
+                if occluded { DVec3::ZERO } else { DVec3::ONE }
+            }
+            DebugMode::WhiteClay => unreachable!(),
+        };
+        color.into()
+    }
+}
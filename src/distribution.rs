@@ -0,0 +1,217 @@
+#![allow(dead_code)]
+
+/// Piecewise constant distribution over [0, 1), proportional to a tabulated function, for
+/// importance sampling it.
+pub struct Distribution1D {
+    func: Vec<f64>,
+    cdf: Vec<f64>,
+    integral: f64,
+}
+
+impl Distribution1D {
+    pub fn new(func: Vec<f64>) -> Self {
+        let n = func.len();
+        let mut cdf = vec![0.0; n + 1];
+        for i in 0..n {
+            cdf[i + 1] = cdf[i] + func[i].abs() / n as f64;
+        }
+        let integral = cdf[n];
+        for (i, c) in cdf.iter_mut().enumerate() {
+            // fall back to uniform for an all zero function
+            *c = if integral == 0.0 {
+                i as f64 / n as f64
+            } else {
+                *c / integral
+            };
+        }
+        Self {
+            func,
+            cdf,
+            integral,
+        }
+    }
+
+    pub fn integral(&self) -> f64 {
+        self.integral
+    }
+
+    /// Maps the uniform `u` to a point distributed like the function. Returns the point, its
+    /// density and the index of the piece it lies in.
+    pub fn sample(&self, u: f64) -> (f64, f64, usize) {
+        let n = self.func.len();
+        let index = (self.cdf.partition_point(|c| *c <= u) - 1).min(n - 1);
+        let width = self.cdf[index + 1] - self.cdf[index];
+        let du = if width > 0.0 {
+            (u - self.cdf[index]) / width
+        } else {
+            0.0
+        };
+        let x = ((index as f64 + du) / n as f64).min(1.0 - f64::EPSILON);
+        (x, self.pdf_at(index), index)
+    }
+
+    pub fn pdf(&self, x: f64) -> f64 {
+        let index = ((x * self.func.len() as f64) as usize).min(self.func.len() - 1);
+        self.pdf_at(index)
+    }
+
+    /// Integral of the function from 0 to `x`.
+    pub fn integral_to(&self, x: f64) -> f64 {
+        let n = self.func.len();
+        let x = x.clamp(0.0, 1.0);
+        let index = ((x * n as f64) as usize).min(n - 1);
+        self.cdf[index] * self.integral + self.func[index].abs() * (x - index as f64 / n as f64)
+    }
+
+    /// Like `sample`, but restricted to [`x0`, `x1`], which the function must not be zero
+    /// all over.
+    pub fn sample_range(&self, u: f64, x0: f64, x1: f64) -> f64 {
+        let (f0, f1) = (self.integral_to(x0), self.integral_to(x1));
+        let (x, _, _) = self.sample((f0 + u * (f1 - f0)) / self.integral);
+        x.clamp(x0, x1)
+    }
+
+    fn pdf_at(&self, index: usize) -> f64 {
+        if self.integral == 0.0 {
+            return 1.0;
+        }
+        self.func[index].abs() / self.integral
+    }
+}
+
+/// Piecewise constant distribution over [0, 1)², from a function tabulated in rows. Samples
+/// a row from the marginal distribution first and then a column within it.
+pub struct Distribution2D {
+    rows: Vec<Distribution1D>,
+    marginal: Distribution1D,
+    // summed-area table, the integral from the origin to every grid corner
+    sat: Vec<f64>,
+}
+
+impl Distribution2D {
+    pub fn new(func: &[f64], width: usize, height: usize) -> Self {
+        let rows: Vec<_> = func
+            .chunks_exact(width)
+            .take(height)
+            .map(|row| Distribution1D::new(row.to_vec()))
+            .collect();
+        let marginal = Distribution1D::new(rows.iter().map(|row| row.integral()).collect());
+
+        let mut sat = vec![0.0; (width + 1) * (height + 1)];
+        let cell_area = 1.0 / (width * height) as f64;
+        for y in 0..height {
+            for x in 0..width {
+                sat[(y + 1) * (width + 1) + x + 1] = func[y * width + x].abs() * cell_area
+                    + sat[y * (width + 1) + x + 1]
+                    + sat[(y + 1) * (width + 1) + x]
+                    - sat[y * (width + 1) + x];
+            }
+        }
+        Self {
+            rows,
+            marginal,
+            sat,
+        }
+    }
+
+    /// Returns the point, as (column, row) coordinates, and its density.
+    pub fn sample(&self, u: (f64, f64)) -> ((f64, f64), f64) {
+        let (y, pdf_y, row) = self.marginal.sample(u.1);
+        let (x, pdf_x, _) = self.rows[row].sample(u.0);
+        ((x, y), pdf_x * pdf_y)
+    }
+
+    pub fn pdf(&self, (x, y): (f64, f64)) -> f64 {
+        let row = ((y * self.rows.len() as f64) as usize).min(self.rows.len() - 1);
+        self.rows[row].pdf(x) * self.marginal.pdf(y)
+    }
+
+    /// Integral of the function over the window [`x0`, `x1`] × [`y0`, `y1`].
+    pub fn window_integral(&self, (x0, x1): (f64, f64), (y0, y1): (f64, f64)) -> f64 {
+        (self.integral_to(x1, y1) - self.integral_to(x0, y1) - self.integral_to(x1, y0)
+            + self.integral_to(x0, y0))
+        .max(0.0)
+    }
+
+    /// Like `sample`, restricted to a window. Returns none if the function is zero all over
+    /// it, the density is relative to the window.
+    pub fn sample_window(
+        &self,
+        u: (f64, f64),
+        (x0, x1): (f64, f64),
+        (y0, y1): (f64, f64),
+    ) -> Option<((f64, f64), f64)> {
+        let total = self.window_integral((x0, x1), (y0, y1));
+        if total <= 0.0 {
+            return None;
+        }
+
+        // invert the part of the window below y, which is linear within rows
+        let below = |y: f64| self.window_integral((x0, x1), (y0, y));
+        let target = u.1 * total;
+        let height = self.rows.len();
+        let first = ((y0 * height as f64) as usize).min(height - 1);
+        let last = ((y1 * height as f64) as usize).min(height - 1);
+        // binary search for the first row ending above the target
+        let (mut row, mut end) = (first, last);
+        while row < end {
+            let mid = (row + end) / 2;
+            if below((mid + 1) as f64 / height as f64) <= target {
+                row = mid + 1;
+            } else {
+                end = mid;
+            }
+        }
+        let low = y0.max(row as f64 / height as f64);
+        let high = y1.min((row + 1) as f64 / height as f64);
+        let (below_low, below_high) = (below(low), below(high));
+        let t = if below_high > below_low {
+            (target - below_low) / (below_high - below_low)
+        } else {
+            0.5
+        };
+        let y = low + t.clamp(0.0, 1.0) * (high - low);
+        if self.rows[row].integral_to(x1) <= self.rows[row].integral_to(x0) {
+            return None;
+        }
+        let x = self.rows[row].sample_range(u.0, x0, x1);
+
+        let pos = (x, y);
+        Some((pos, self.value(pos) / total))
+    }
+
+    pub fn pdf_window(
+        &self,
+        (x, y): (f64, f64),
+        (x0, x1): (f64, f64),
+        (y0, y1): (f64, f64),
+    ) -> f64 {
+        if x < x0 || x > x1 || y < y0 || y > y1 {
+            return 0.0;
+        }
+        let total = self.window_integral((x0, x1), (y0, y1));
+        if total <= 0.0 {
+            return 0.0;
+        }
+        self.value((x, y)) / total
+    }
+
+    fn value(&self, (x, y): (f64, f64)) -> f64 {
+        let row = &self.rows[((y * self.rows.len() as f64) as usize).min(self.rows.len() - 1)];
+        row.func[((x * row.func.len() as f64) as usize).min(row.func.len() - 1)].abs()
+    }
+
+    // integral over [0, `x`] × [0, `y`], bilinear between the corners of the table
+    fn integral_to(&self, x: f64, y: f64) -> f64 {
+        let height = self.rows.len();
+        let width = self.rows[0].func.len();
+        let x = x.clamp(0.0, 1.0) * width as f64;
+        let y = y.clamp(0.0, 1.0) * height as f64;
+        let (i, j) = ((x as usize).min(width - 1), (y as usize).min(height - 1));
+        let (tx, ty) = (x - i as f64, y - j as f64);
+        let at = |i: usize, j: usize| self.sat[j * (width + 1) + i];
+        let bottom = at(i, j) + (at(i + 1, j) - at(i, j)) * tx;
+        let top = at(i, j + 1) + (at(i + 1, j + 1) - at(i, j + 1)) * tx;
+        bottom + (top - bottom) * ty
+    }
+}
@@ -0,0 +1,274 @@
+#![allow(dead_code)]
+
+use crate::filter::Filter;
+use crate::glam_ext::DVec3Ext;
+use crate::integrator::Radiance;
+use crate::texture::Texture;
+
+use glam::DVec3;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Pixel {
+    // filter weighted sum of the samples splatted into this pixel
+    pub sum: DVec3,
+    pub weight_sum: f64,
+    // filter weighted sum of the samples' alpha
+    pub alpha_sum: f64,
+    // running luminance statistics of the samples taken for this pixel, for the noise estimate
+    pub lum_sum: f64,
+    pub lum_sq_sum: f64,
+    pub samples: u32,
+}
+
+impl Pixel {
+    pub fn add_sample(&mut self, color: DVec3) {
+        let lum = color.luminance();
+        self.lum_sum += lum;
+        self.lum_sq_sum += lum * lum;
+        self.samples += 1;
+    }
+
+    pub fn splat(&mut self, color: DVec3, alpha: f64, weight: f64) {
+        self.sum += color * weight;
+        self.alpha_sum += alpha * weight;
+        self.weight_sum += weight;
+    }
+
+    pub fn mean(&self) -> DVec3 {
+        // negative filter lobes can leave a pixel without meaningful weight
+        if self.weight_sum <= 0.0 {
+            return DVec3::ZERO;
+        }
+        self.sum / self.weight_sum
+    }
+
+    pub fn alpha(&self) -> f64 {
+        // opaque unless samples said otherwise
+        if self.weight_sum <= 0.0 {
+            return 1.0;
+        }
+        (self.alpha_sum / self.weight_sum).clamp(0.0, 1.0)
+    }
+
+    /// Standard error of the mean luminance relative to the mean itself.
+    pub fn relative_error(&self) -> f64 {
+        if self.samples < 2 {
+            return f64::INFINITY;
+        }
+        let n = self.samples as f64;
+        let mean = self.lum_sum / n;
+        let variance = ((self.lum_sq_sum - mean * self.lum_sum) / (n - 1.0)).max(0.0);
+        // keep almost black pixels from demanding endless samples
+        (variance / n).sqrt() / mean.max(1e-3)
+    }
+}
+
+/// Per-pixel sample accumulator, resolved into a `Texture` once rendering is done.
+/// The film covers the whole image, so samples near the edge of the region being rendered
+/// are splatted into the neighbouring pixels as well.
+///
+/// Besides the full image, the film keeps one layer per light group with only the light of
+/// that group. The layers share the filter weights of the pixels, so they add up to the full
+/// image exactly.
+pub struct Film {
+    pub width: u32,
+    pub height: u32,
+    pub filter: Filter,
+    // see `World::light_groups`
+    pub light_groups: Vec<String>,
+    pixels: Vec<Pixel>,
+    // filter weighted sums per light group, one after the other for every pixel
+    layers: Vec<DVec3>,
+    // unnormalized contributions per light group, e.g. from Metropolis chains, added to the
+    // image scaled by `splat_scale`
+    splats: Vec<DVec3>,
+    pub splat_scale: f64,
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32, filter: Filter, light_groups: Vec<String>) -> Self {
+        let layers = vec![DVec3::ZERO; (width * height) as usize * light_groups.len()];
+        let pixels = vec![Pixel::default(); (width * height) as usize];
+        Self::from_pixels(width, height, filter, light_groups, pixels, layers)
+    }
+
+    pub fn from_pixels(
+        width: u32,
+        height: u32,
+        filter: Filter,
+        light_groups: Vec<String>,
+        pixels: Vec<Pixel>,
+        layers: Vec<DVec3>,
+    ) -> Self {
+        assert_eq!(pixels.len(), (width * height) as usize);
+        assert_eq!(layers.len(), pixels.len() * light_groups.len());
+        Self {
+            width,
+            height,
+            filter,
+            splats: vec![DVec3::ZERO; layers.len()],
+            light_groups,
+            pixels,
+            layers,
+            splat_scale: 1.0,
+        }
+    }
+
+    pub fn pixels(&self) -> &[Pixel] {
+        &self.pixels
+    }
+
+    /// The filter weighted sums of every light group for every pixel, see `from_pixels`.
+    pub fn layer_sums(&self) -> &[DVec3] {
+        &self.layers
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> &Pixel {
+        &self.pixels[(y * self.width + x) as usize]
+    }
+
+    /// Records a sample taken for pixel (`x`, `y`) at `offset` from its center and splats it
+    /// into every pixel within the filter radius. `radiance` is premultiplied by `alpha`.
+    pub fn add_sample(
+        &mut self,
+        x: u32,
+        y: u32,
+        offset: (f64, f64),
+        radiance: &Radiance,
+        alpha: f64,
+    ) -> &Pixel {
+        let color = radiance.total();
+        let groups = self.light_groups.len();
+        // continuous image coordinates, pixel centers are at .5
+        let px = x as f64 + 0.5 + offset.0;
+        let py = y as f64 + 0.5 + offset.1;
+        let radius = self.filter.radius();
+
+        let x0 = (px - 0.5 - radius).ceil().max(0.0) as u32;
+        let x1 = ((px - 0.5 + radius).floor() as i64).min(self.width as i64 - 1);
+        let y0 = (py - 0.5 - radius).ceil().max(0.0) as u32;
+        let y1 = ((py - 0.5 + radius).floor() as i64).min(self.height as i64 - 1);
+
+        for sy in y0 as i64..=y1 {
+            for sx in x0 as i64..=x1 {
+                let weight = self
+                    .filter
+                    .eval(px - (sx as f64 + 0.5), py - (sy as f64 + 0.5));
+                if weight != 0.0 {
+                    let index = (sy * self.width as i64 + sx) as usize;
+                    self.pixels[index].splat(color, alpha, weight);
+                    for (group, layer) in self.layers[index * groups..][..groups]
+                        .iter_mut()
+                        .enumerate()
+                    {
+                        *layer += radiance.group(group) * weight;
+                    }
+                }
+            }
+        }
+
+        let pixel = &mut self.pixels[(y * self.width + x) as usize];
+        pixel.add_sample(color);
+        pixel
+    }
+
+    /// Adds `radiance` to the pixel containing `pos`, in pixels from the upper left corner.
+    /// Splats are not filtered.
+    pub fn add_splat(&mut self, pos: (f64, f64), radiance: &Radiance) {
+        let x = (pos.0 as u32).min(self.width - 1);
+        let y = (pos.1 as u32).min(self.height - 1);
+        let groups = self.light_groups.len();
+        let index = (y * self.width + x) as usize;
+        for (group, splat) in self.splats[index * groups..][..groups]
+            .iter_mut()
+            .enumerate()
+        {
+            *splat += radiance.group(group);
+        }
+    }
+
+    pub fn min_samples(&self) -> u32 {
+        self.pixels
+            .iter()
+            .map(|pixel| pixel.samples)
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Average of `Pixel::relative_error` over the pixels that have been sampled enough to
+    /// estimate it.
+    pub fn mean_relative_error(&self) -> f64 {
+        let (sum, count) = self
+            .pixels
+            .iter()
+            .map(|pixel| pixel.relative_error())
+            .filter(|error| error.is_finite())
+            .fold((0.0, 0), |(sum, count), error| (sum + error, count + 1));
+        if count == 0 {
+            return f64::INFINITY;
+        }
+        sum / count as f64
+    }
+
+    pub fn to_texture(&self) -> Texture {
+        let groups = self.light_groups.len();
+        let mut texture = Texture::new(self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let index = (y * self.width + x) as usize;
+                let splat: DVec3 = self.splats[index * groups..][..groups].iter().sum();
+                texture.set(x, y, self.pixel(x, y).mean() + splat * self.splat_scale);
+                texture.set_alpha(x, y, self.pixel(x, y).alpha());
+            }
+        }
+        texture
+    }
+
+    /// The image lit by light `group` only, an index into `light_groups`.
+    pub fn layer(&self, group: usize) -> Texture {
+        let groups = self.light_groups.len();
+        let mut texture = Texture::new(self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let index = (y * self.width + x) as usize;
+                let pixel = self.pixel(x, y);
+                // like `Pixel::mean`
+                let mean = if pixel.weight_sum <= 0.0 {
+                    DVec3::ZERO
+                } else {
+                    self.layers[index * groups + group] / pixel.weight_sum
+                };
+                let splat = self.splats[index * groups + group] * self.splat_scale;
+                texture.set(x, y, mean + splat);
+                texture.set_alpha(x, y, pixel.alpha());
+            }
+        }
+        texture
+    }
+
+    /// `layer` of every light group, with its name.
+    pub fn layers(&self) -> Vec<(String, Texture)> {
+        self.light_groups
+            .iter()
+            .enumerate()
+            .map(|(group, name)| (name.clone(), self.layer(group)))
+            .collect()
+    }
+
+    /// Visualizes the number of samples taken per pixel, from blue (none) to red (`max_samples`).
+    pub fn heat_map(&self, max_samples: u32) -> Texture {
+        let mut texture = Texture::new(self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let t = (self.pixel(x, y).samples as f64 / max_samples as f64).clamp(0.0, 1.0);
+                let color = if t < 0.5 {
+                    DVec3::Z.lerp(DVec3::Y, t * 2.0)
+                } else {
+                    DVec3::Y.lerp(DVec3::X, t * 2.0 - 1.0)
+                };
+                texture.set(x, y, color);
+            }
+        }
+        texture
+    }
+}
@@ -0,0 +1,116 @@
+#![allow(dead_code)]
+
+use std::f64::consts::PI;
+
+/// Pixel reconstruction filter. Samples are splatted into every pixel whose center lies
+/// within `radius` (in pixels) and weighted by the filter, which is separable in x and y.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    Box { radius: f64 },
+    Tent { radius: f64 },
+    Gaussian { radius: f64, sigma: f64 },
+    // `b` and `c` of 1/3 are the values recommended by Mitchell and Netravali
+    Mitchell { radius: f64, b: f64, c: f64 },
+    // `radius` doubles as the number of lobes of the windowed sinc
+    Lanczos { radius: f64 },
+    BlackmanHarris { radius: f64 },
+}
+
+impl Default for Filter {
+    // one sample per pixel, a plain average like the renderer always did
+    fn default() -> Self {
+        Filter::Box { radius: 0.5 }
+    }
+}
+
+impl Filter {
+    pub fn mitchell(radius: f64) -> Self {
+        Filter::Mitchell {
+            radius,
+            b: 1.0 / 3.0,
+            c: 1.0 / 3.0,
+        }
+    }
+
+    pub fn gaussian(radius: f64) -> Self {
+        Filter::Gaussian {
+            radius,
+            sigma: radius / 3.0,
+        }
+    }
+
+    pub fn radius(&self) -> f64 {
+        match *self {
+            Filter::Box { radius }
+            | Filter::Tent { radius }
+            | Filter::Gaussian { radius, .. }
+            | Filter::Mitchell { radius, .. }
+            | Filter::Lanczos { radius }
+            | Filter::BlackmanHarris { radius } => radius,
+        }
+    }
+
+    /// Weight of a sample at offset (`x`, `y`) from the pixel center. May be negative for
+    /// the filters with negative lobes.
+    pub fn eval(&self, x: f64, y: f64) -> f64 {
+        self.eval_1d(x) * self.eval_1d(y)
+    }
+
+    fn eval_1d(&self, x: f64) -> f64 {
+        match *self {
+            // half open, so a sample on the border between two pixels only counts once
+            Filter::Box { radius } => {
+                if -radius <= x && x < radius {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Filter::Tent { radius } => (radius - x.abs()).max(0.0),
+            Filter::Gaussian { radius, sigma } => {
+                let gaussian = |x: f64| (-x * x / (2.0 * sigma * sigma)).exp();
+                // shifted so the filter goes to zero at its radius
+                (gaussian(x) - gaussian(radius)).max(0.0)
+            }
+            Filter::Mitchell { radius, b, c } => {
+                let x = (2.0 * x / radius).abs();
+                if x >= 2.0 {
+                    0.0
+                } else if x >= 1.0 {
+                    ((-b - 6.0 * c) * x.powi(3)
+                        + (6.0 * b + 30.0 * c) * x * x
+                        + (-12.0 * b - 48.0 * c) * x
+                        + (8.0 * b + 24.0 * c))
+                        / 6.0
+                } else {
+                    ((12.0 - 9.0 * b - 6.0 * c) * x.powi(3)
+                        + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+                        + (6.0 - 2.0 * b))
+                        / 6.0
+                }
+            }
+            Filter::Lanczos { radius } => {
+                if x.abs() >= radius {
+                    0.0
+                } else {
+                    Self::sinc(x) * Self::sinc(x / radius)
+                }
+            }
+            Filter::BlackmanHarris { radius } => {
+                if x.abs() >= radius {
+                    return 0.0;
+                }
+                let t = (x + radius) / (2.0 * radius);
+                0.35875 - 0.48829 * (2.0 * PI * t).cos() + 0.14128 * (4.0 * PI * t).cos()
+                    - 0.01168 * (6.0 * PI * t).cos()
+            }
+        }
+    }
+
+    fn sinc(x: f64) -> f64 {
+        if x.abs() < 1e-5 {
+            return 1.0;
+        }
+        (PI * x).sin() / (PI * x)
+    }
+}
@@ -1,20 +1,20 @@
 use std::f64::consts::PI;
 
-use fastrand::Rng;
+use crate::sampler;
+
 use glam::DVec3;
 
 pub trait DVec3Ext {
     fn near_zero(self) -> bool;
     fn random() -> Self;
+    fn luminance(self) -> f64;
 }
 
 impl DVec3Ext for DVec3 {
     fn random() -> Self {
-        let mut rng = Rng::new();
-
         // theta for azimuthal, phi for polar
-        let theta = rng.f64() * 2.0 * PI;
-        let cos_phi = rng.f64() * 2.0 - 1.0;
+        let theta = sampler::f64() * 2.0 * PI;
+        let cos_phi = sampler::f64() * 2.0 - 1.0;
         let sin_phi = (1.0 - cos_phi * cos_phi).sqrt();
 
         DVec3::new(sin_phi * theta.cos(), sin_phi * theta.sin(), cos_phi)
@@ -24,4 +24,9 @@ impl DVec3Ext for DVec3 {
         let s = 1e-8;
         self.x.abs() < s && self.y.abs() < s && self.z.abs() < s
     }
+
+    fn luminance(self) -> f64 {
+        // Rec. 709 weights
+        DVec3::dot(self, DVec3::new(0.2126, 0.7152, 0.0722))
+    }
 }
@@ -3,13 +3,130 @@
 use std::f64::consts::PI;
 use std::ops;
 
+use crate::glam_ext::DVec3Ext;
+use crate::light::DEFAULT_LIGHT_GROUP;
+use crate::light_bvh::LightBounds;
 use crate::material::Material;
-use crate::ray::Ray;
+use crate::ray::{Ray, RayKind};
+use crate::sampler;
+use crate::texture::TextureContext;
 
 use glam::{DVec2, DVec3};
 
 pub trait Hittable {
     fn hit(&self, ray: &Ray) -> Option<HitRecord<'_>>;
+
+    fn is_emissive(&self) -> bool {
+        false
+    }
+
+    fn light_group(&self) -> &str {
+        DEFAULT_LIGHT_GROUP
+    }
+
+    /// Whether it's a holdout or a shadow catcher.
+    fn is_matte(&self) -> bool {
+        false
+    }
+
+    fn area(&self) -> f64 {
+        0.0
+    }
+
+    /// Picks a point uniformly over the surface, for sampling emitters.
+    fn sample_surface(&self) -> Option<HitRecord<'_>> {
+        None
+    }
+
+    /// For emitters to be sampled through the `LightBvh`.
+    fn light_bounds(&self) -> Option<LightBounds> {
+        None
+    }
+
+    fn visibility(&self) -> Visibility {
+        Visibility::default()
+    }
+
+    fn light_linking(&self) -> &LightLinking {
+        &LightLinking::All
+    }
+
+    /// How position and shading normal change with the texture coordinates at a hit.
+    fn derivatives(&self, _hit_record: &HitRecord) -> Derivatives {
+        Derivatives::default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Derivatives {
+    pub dpdu: DVec3,
+    pub dpdv: DVec3,
+    pub dndu: DVec3,
+    pub dndv: DVec3,
+}
+
+/// How a hit changes towards the neighbouring pixels, from the differentials of its ray.
+#[derive(Debug, Clone, Copy)]
+pub struct HitDifferentials {
+    pub dpdx: DVec3,
+    pub dpdy: DVec3,
+    pub duv_dx: DVec2,
+    pub duv_dy: DVec2,
+    pub dndx: DVec3,
+    pub dndy: DVec3,
+}
+
+/// The kinds of rays that see an object.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Visibility {
+    pub camera: bool,
+    // whether it blocks shadow rays
+    pub shadow: bool,
+    // in reflections and refractions
+    pub specular: bool,
+    pub diffuse: bool,
+}
+
+impl Default for Visibility {
+    fn default() -> Self {
+        Self {
+            camera: true,
+            shadow: true,
+            specular: true,
+            diffuse: true,
+        }
+    }
+}
+
+impl Visibility {
+    pub fn sees(&self, kind: RayKind) -> bool {
+        match kind {
+            RayKind::Camera => self.camera,
+            RayKind::Diffuse => self.diffuse,
+            RayKind::Specular => self.specular,
+            RayKind::Shadow => self.shadow,
+        }
+    }
+}
+
+/// The light groups that shine on an object, see `LightSource::light_group`. Giving a light
+/// a group of its own links it individually.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum LightLinking {
+    #[default]
+    All,
+    Include(Vec<String>),
+    Exclude(Vec<String>),
+}
+
+impl LightLinking {
+    pub fn includes(&self, light_group: &str) -> bool {
+        match self {
+            LightLinking::All => true,
+            LightLinking::Include(groups) => groups.iter().any(|group| group == light_group),
+            LightLinking::Exclude(groups) => !groups.iter().any(|group| group == light_group),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -21,17 +138,87 @@ pub enum Facing {
 pub struct HitRecord<'a> {
     pub t: f64,
     pub pos: DVec3,
-    // normalized
+    // normalized shading normal
     pub normal: DVec3,
+    // normalized normal of the actual surface, without normal interpolation
+    pub geometric_normal: DVec3,
     pub tex_coords: DVec2,
+    // texture footprint, set by `World::trace` for rays with differentials
+    pub duv_dx: DVec2,
+    pub duv_dy: DVec2,
+    // barycentric coordinates of the second and third vertex on triangles, zero otherwise
+    pub barycentric: DVec2,
     pub facing: Facing,
     pub material: &'a dyn Material,
+    pub object: &'a dyn Hittable,
+}
+
+impl HitRecord<'_> {
+    /// The normal on the side the ray came from.
+    pub fn facing_normal(&self) -> DVec3 {
+        match self.facing {
+            Facing::Front => self.normal,
+            Facing::Back => -self.normal,
+        }
+    }
+
+    pub fn texture_context(&self) -> TextureContext {
+        TextureContext {
+            pos: self.pos,
+            uv: self.tex_coords,
+            duv_dx: self.duv_dx,
+            duv_dy: self.duv_dy,
+        }
+    }
+
+    /// Where the offset rays of `ray` meet the tangent plane at the hit, expressed as
+    /// changes in position, texture coordinates and normal.
+    pub fn differentials(&self, ray: &Ray) -> Option<HitDifferentials> {
+        let differential = ray.differential?;
+        let n = self.geometric_normal;
+        let offset = |origin: DVec3, dir: DVec3| {
+            let t = DVec3::dot(n, self.pos - origin) / DVec3::dot(n, dir);
+            t.is_finite().then(|| origin + dir * t - self.pos)
+        };
+        let dpdx = offset(differential.rx_origin, differential.rx_dir)?;
+        let dpdy = offset(differential.ry_origin, differential.ry_dir)?;
+
+        // least squares fit of dpdx = dpdu * du + dpdv * dv
+        let Derivatives {
+            dpdu,
+            dpdv,
+            dndu,
+            dndv,
+        } = self.object.derivatives(self);
+        let (a00, a01, a11) = (dpdu.length_squared(), dpdu.dot(dpdv), dpdv.length_squared());
+        let det = a00 * a11 - a01 * a01;
+        let solve = |dp: DVec3| {
+            if det.abs() < 1e-12 {
+                return DVec2::ZERO;
+            }
+            let (b0, b1) = (dpdu.dot(dp), dpdv.dot(dp));
+            let duv = DVec2::new(a11 * b0 - a01 * b1, a00 * b1 - a01 * b0) / det;
+            duv.clamp(DVec2::splat(-1e8), DVec2::splat(1e8))
+        };
+        let (duv_dx, duv_dy) = (solve(dpdx), solve(dpdy));
+
+        Some(HitDifferentials {
+            dpdx,
+            dpdy,
+            duv_dx,
+            duv_dy,
+            dndx: dndu * duv_dx.x + dndv * duv_dx.y,
+            dndy: dndu * duv_dy.x + dndv * duv_dy.y,
+        })
+    }
 }
 
 pub struct Sphere<'a> {
     center: DVec3,
     radius: f64,
     material: &'a dyn Material,
+    pub visibility: Visibility,
+    pub light_linking: LightLinking,
 }
 
 impl<'a> Sphere<'a> {
@@ -40,6 +227,8 @@ impl<'a> Sphere<'a> {
             center,
             radius,
             material,
+            visibility: Visibility::default(),
+            light_linking: LightLinking::All,
         }
     }
 
@@ -76,9 +265,14 @@ impl Hittable for Sphere<'_> {
                 t: t1,
                 pos,
                 normal,
+                geometric_normal: normal,
                 tex_coords: Self::get_uv(normal),
+                duv_dx: DVec2::ZERO,
+                duv_dy: DVec2::ZERO,
+                barycentric: DVec2::ZERO,
                 facing: Facing::Front,
                 material: self.material,
+                object: self,
             })
         } else {
             let pos = ray.at(t2);
@@ -87,12 +281,87 @@ impl Hittable for Sphere<'_> {
                 t: t2,
                 pos,
                 normal,
+                geometric_normal: normal,
                 tex_coords: Self::get_uv(normal),
+                duv_dx: DVec2::ZERO,
+                duv_dy: DVec2::ZERO,
+                barycentric: DVec2::ZERO,
                 facing: Facing::Back,
                 material: self.material,
+                object: self,
             })
         }
     }
+
+    fn is_emissive(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    fn light_group(&self) -> &str {
+        self.material.light_group()
+    }
+
+    fn is_matte(&self) -> bool {
+        self.material.is_holdout() || self.material.is_shadow_catcher()
+    }
+
+    fn visibility(&self) -> Visibility {
+        self.visibility
+    }
+
+    fn light_linking(&self) -> &LightLinking {
+        &self.light_linking
+    }
+
+    fn area(&self) -> f64 {
+        4.0 * PI * self.radius * self.radius
+    }
+
+    fn derivatives(&self, hit_record: &HitRecord) -> Derivatives {
+        // from the mapping in `get_uv`, degenerate at the poles
+        let n = (hit_record.pos - self.center) / self.radius;
+        let sin_theta = (n.x * n.x + n.z * n.z).sqrt().max(1e-9);
+        let dndu = DVec3::new(n.z, 0.0, -n.x) * 2.0 * PI;
+        let dndv = DVec3::new(-n.y * n.x, sin_theta * sin_theta, -n.y * n.z) * PI / sin_theta;
+        Derivatives {
+            dpdu: dndu * self.radius,
+            dpdv: dndv * self.radius,
+            dndu,
+            dndv,
+        }
+    }
+
+    fn sample_surface(&self) -> Option<HitRecord<'_>> {
+        let normal = DVec3::random();
+        Some(HitRecord {
+            t: 0.0,
+            pos: self.center + normal * self.radius,
+            normal,
+            geometric_normal: normal,
+            tex_coords: Self::get_uv(normal),
+            duv_dx: DVec2::ZERO,
+            duv_dy: DVec2::ZERO,
+            barycentric: DVec2::ZERO,
+            facing: Facing::Front,
+            material: self.material,
+            object: self,
+        })
+    }
+
+    fn light_bounds(&self) -> Option<LightBounds> {
+        if !self.is_emissive() {
+            return None;
+        }
+        Some(LightBounds {
+            min: self.center - self.radius,
+            max: self.center + self.radius,
+            power: self.material.average_emission().luminance() * self.area() * PI,
+            // shines in every direction
+            axis: DVec3::Y,
+            cos_theta_o: -1.0,
+            cos_theta_e: 0.0,
+        })
+    }
 }
 
 pub struct Triangle<'a> {
@@ -102,6 +371,8 @@ pub struct Triangle<'a> {
     v1: DVec3,
     v2: DVec3,
     material: &'a dyn Material,
+    pub visibility: Visibility,
+    pub light_linking: LightLinking,
 }
 
 impl<'a> Triangle<'a> {
@@ -120,6 +391,8 @@ impl<'a> Triangle<'a> {
             v1,
             v2,
             material,
+            visibility: Visibility::default(),
+            light_linking: LightLinking::All,
         }
     }
 
@@ -133,6 +406,8 @@ impl<'a> Triangle<'a> {
             v1,
             v2,
             material,
+            visibility: Visibility::default(),
+            light_linking: LightLinking::All,
         }
     }
 
@@ -180,14 +455,130 @@ impl Hittable for Triangle<'_> {
 
         let normal = Self::interpolate(&self.normal, (u, v)).normalize();
         let tex_coords = Self::interpolate(&self.tex_coords, (u, v));
+        let facing = if DVec3::dot(ray.dir, normal) < 0.0 {
+            Facing::Front
+        } else {
+            Facing::Back
+        };
 
         Some(HitRecord {
             t,
             pos: ray.at(t),
             normal,
+            geometric_normal: DVec3::cross(self.v1, self.v2).normalize(),
             tex_coords,
-            facing: Facing::Front, //todo
+            duv_dx: DVec2::ZERO,
+            duv_dy: DVec2::ZERO,
+            barycentric: DVec2::new(u, v),
+            facing,
             material: self.material,
+            object: self,
+        })
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    fn light_group(&self) -> &str {
+        self.material.light_group()
+    }
+
+    fn is_matte(&self) -> bool {
+        self.material.is_holdout() || self.material.is_shadow_catcher()
+    }
+
+    fn visibility(&self) -> Visibility {
+        self.visibility
+    }
+
+    fn light_linking(&self) -> &LightLinking {
+        &self.light_linking
+    }
+
+    fn area(&self) -> f64 {
+        DVec3::cross(self.v1, self.v2).length() / 2.0
+    }
+
+    fn derivatives(&self, _hit_record: &HitRecord) -> Derivatives {
+        // constant over the triangle, zero without texture coordinates
+        let duv02 = self.tex_coords[0] - self.tex_coords[2];
+        let duv12 = self.tex_coords[1] - self.tex_coords[2];
+        let det = duv02.x * duv12.y - duv02.y * duv12.x;
+        if det.abs() < 1e-12 {
+            return Derivatives::default();
+        }
+        let solve = |values: &[DVec3; 3]| {
+            let (d02, d12) = (values[0] - values[2], values[1] - values[2]);
+            (
+                (d02 * duv12.y - d12 * duv02.y) / det,
+                (d12 * duv02.x - d02 * duv12.x) / det,
+            )
+        };
+        let (dpdu, dpdv) = solve(&self.vertices);
+        let (dndu, dndv) = solve(&self.normal);
+        Derivatives {
+            dpdu,
+            dpdv,
+            dndu,
+            dndv,
+        }
+    }
+
+    fn sample_surface(&self) -> Option<HitRecord<'_>> {
+        // uniform barycentric coordinates
+        let r1 = sampler::f64().sqrt();
+        let r2 = sampler::f64();
+        let (u, v) = (r1 * (1.0 - r2), r1 * r2);
+
+        Some(HitRecord {
+            t: 0.0,
+            pos: Self::interpolate(&self.vertices, (u, v)),
+            normal: Self::interpolate(&self.normal, (u, v)).normalize(),
+            geometric_normal: DVec3::cross(self.v1, self.v2).normalize(),
+            tex_coords: Self::interpolate(&self.tex_coords, (u, v)),
+            duv_dx: DVec2::ZERO,
+            duv_dy: DVec2::ZERO,
+            barycentric: DVec2::new(u, v),
+            facing: Facing::Front,
+            material: self.material,
+            object: self,
+        })
+    }
+
+    fn light_bounds(&self) -> Option<LightBounds> {
+        if !self.is_emissive() {
+            return None;
+        }
+        // the face rather than the interpolated shading normals, on the side they emit to
+        let face = (self.vertices[1] - self.vertices[0])
+            .cross(self.vertices[2] - self.vertices[0])
+            .normalize();
+        let shading = self.normal[0] + self.normal[1] + self.normal[2];
+        let axis = if DVec3::dot(face, shading) < 0.0 {
+            -face
+        } else {
+            face
+        };
+        Some(LightBounds {
+            min: self.vertices[0].min(self.vertices[1]).min(self.vertices[2]),
+            max: self.vertices[0].max(self.vertices[1]).max(self.vertices[2]),
+            power: self.material.average_emission().luminance()
+                * self.area()
+                * PI
+                * if self.material.is_two_sided() {
+                    2.0
+                } else {
+                    1.0
+                },
+            axis,
+            // shining from both sides takes the whole sphere of directions
+            cos_theta_o: if self.material.is_two_sided() {
+                -1.0
+            } else {
+                1.0
+            },
+            cos_theta_e: 0.0,
         })
     }
 }
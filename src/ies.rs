@@ -0,0 +1,167 @@
+#![allow(dead_code)]
+
+use std::io;
+use std::path::Path;
+
+use glam::DVec3;
+
+/// Angular intensity distribution of a light fixture, read from an IES LM-63 file. Only
+/// type C photometry is supported, which covers nearly all architectural fixtures.
+///
+/// In the fixture's frame the vertical angle is measured from straight down (-y), and the
+/// horizontal angle around the y axis, starting at +x and turning towards -z.
+pub struct IesProfile {
+    // in degrees, ascending
+    pub vertical_angles: Vec<f64>,
+    pub horizontal_angles: Vec<f64>,
+    // in candela, one row of vertical angles per horizontal angle
+    pub candela: Vec<Vec<f64>>,
+}
+
+impl IesProfile {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        // the header is often Latin-1, but only the numbers matter
+        let bytes = std::fs::read(path)?;
+        Self::parse(&String::from_utf8_lossy(&bytes))
+    }
+
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let invalid =
+            |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_owned());
+
+        // keywords like [MANUFAC] come first, the data starts after the TILT line
+        let mut lines = text.lines();
+        let tilt = lines
+            .find_map(|line| line.trim().strip_prefix("TILT="))
+            .ok_or_else(|| invalid("missing TILT line"))?;
+        let mut values = lines
+            .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+            .filter(|token| !token.is_empty())
+            .map(|token| token.parse::<f64>().map_err(|_| invalid("invalid number")));
+        let mut next = || {
+            values
+                .next()
+                .unwrap_or_else(|| Err(invalid("unexpected end of file")))
+        };
+
+        if tilt.trim() == "INCLUDE" {
+            // lamp to luminaire geometry, then angles and multipliers we ignore
+            next()?;
+            let pairs = next()? as usize;
+            for _ in 0..2 * pairs {
+                next()?;
+            }
+        } else if tilt.trim() != "NONE" {
+            return Err(invalid("TILT files are not supported"));
+        }
+
+        let _lamps = next()?;
+        let _lumens_per_lamp = next()?;
+        let multiplier = next()?;
+        let vertical_count = next()? as usize;
+        let horizontal_count = next()? as usize;
+        let photometric_type = next()?;
+        // units, width, length and height of the luminous opening
+        for _ in 0..4 {
+            next()?;
+        }
+        let ballast_factor = next()?;
+        // ballast lamp photometric factor, input watts
+        for _ in 0..2 {
+            next()?;
+        }
+        if photometric_type != 1.0 {
+            return Err(invalid("only type C photometry is supported"));
+        }
+        if vertical_count == 0 || horizontal_count == 0 {
+            return Err(invalid("no angles"));
+        }
+
+        let vertical_angles = (0..vertical_count)
+            .map(|_| next())
+            .collect::<io::Result<Vec<_>>>()?;
+        let horizontal_angles = (0..horizontal_count)
+            .map(|_| next())
+            .collect::<io::Result<Vec<_>>>()?;
+        let candela = (0..horizontal_count)
+            .map(|_| {
+                (0..vertical_count)
+                    .map(|_| Ok(next()? * multiplier * ballast_factor))
+                    .collect()
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        Ok(Self {
+            vertical_angles,
+            horizontal_angles,
+            candela,
+        })
+    }
+
+    /// Intensity in candela towards `dir`, normalized and in the fixture's frame.
+    pub fn intensity(&self, dir: DVec3) -> f64 {
+        let vertical = (-dir.y).clamp(-1.0, 1.0).acos().to_degrees();
+        // outside the measured range, e.g. above a downlight
+        if vertical < self.vertical_angles[0] || vertical > *self.vertical_angles.last().unwrap() {
+            return 0.0;
+        }
+        let horizontal = f64::atan2(-dir.z, dir.x).to_degrees().rem_euclid(360.0);
+        let horizontal = self.fold_horizontal(horizontal);
+
+        // bilinear between the closest measured angles
+        let (v0, v1, vt) = interval(&self.vertical_angles, vertical);
+        let (h0, h1, ht) = self.horizontal_interval(horizontal);
+        let row = |h: usize| lerp(self.candela[h][v0], self.candela[h][v1], vt);
+        lerp(row(h0), row(h1), ht)
+    }
+
+    // like `interval`, but full-circle data that stops short of 360° wraps around from its
+    // last angle to 0°
+    fn horizontal_interval(&self, angle: f64) -> (usize, usize, f64) {
+        let angles = &self.horizontal_angles;
+        let last = *angles.last().unwrap();
+        if angles[0] == 0.0 && last > 180.0 && last < 360.0 && angle > last {
+            return (angles.len() - 1, 0, (angle - last) / (360.0 - last));
+        }
+        interval(angles, angle)
+    }
+
+    // maps a horizontal angle into the measured range, which only covers one half or
+    // quadrant of symmetric fixtures
+    fn fold_horizontal(&self, angle: f64) -> f64 {
+        let last = *self.horizontal_angles.last().unwrap();
+        if last == 0.0 {
+            // rotationally symmetric
+            0.0
+        } else if last == 90.0 {
+            let angle = if angle > 180.0 { 360.0 - angle } else { angle };
+            if angle > 90.0 { 180.0 - angle } else { angle }
+        } else if last == 180.0 {
+            if angle > 180.0 { 360.0 - angle } else { angle }
+        } else {
+            angle
+        }
+    }
+}
+
+// indices of the measured angles around `angle`, and the position between them
+fn interval(angles: &[f64], angle: f64) -> (usize, usize, f64) {
+    if angles.len() < 2 {
+        return (0, 0, 0.0);
+    }
+    let i = angles
+        .partition_point(|&a| a <= angle)
+        .clamp(1, angles.len() - 1)
+        - 1;
+    let width = angles[i + 1] - angles[i];
+    let t = if width > 0.0 {
+        (angle - angles[i]) / width
+    } else {
+        0.0
+    };
+    (i, i + 1, t.clamp(0.0, 1.0))
+}
+
+fn lerp(a: f64, b: f64, t: f64) -> f64 {
+    a + (b - a) * t
+}
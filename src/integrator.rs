@@ -0,0 +1,492 @@
+#![allow(dead_code)]
+
+use std::f64::consts::PI;
+use std::ops::{AddAssign, Mul};
+
+use crate::debug::DebugMode;
+use crate::glam_ext::DVec3Ext;
+use crate::hittable::{HitRecord, Hittable};
+use crate::light::DEFAULT_LIGHT_GROUP;
+use crate::material::Lambertian;
+use crate::ray::{Ray, RayKind};
+use crate::sampler;
+use crate::world::World;
+
+use glam::DVec3;
+
+pub trait Integrator {
+    /// Radiance arriving at the camera along `ray`.
+    fn li(&self, ray: &Ray, world: &World) -> Radiance;
+
+    /// Called before sample `iteration` of every pixel is taken, counting from zero, for
+    /// integrators that prepare something shared by all pixels.
+    fn start_iteration(&mut self, _world: &World, _iteration: u32) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum IntegratorKind {
+    #[default]
+    PathTracer,
+    Bidirectional,
+    // `photons` per sample per pixel, starting at a search radius of `radius`
+    PhotonMapping {
+        photons: u32,
+        radius: f64,
+        alpha: f64,
+    },
+    // see `Metropolis`. Chains are splatted whole, so progressive rendering and checkpoints
+    // are not supported with it
+    Metropolis {
+        bootstrap: u32,
+        chains: u32,
+        large_step_probability: f64,
+        sigma: f64,
+    },
+    // inspection modes, see `DebugIntegrator`
+    Debug(DebugMode),
+}
+
+impl IntegratorKind {
+    /// Whether it can render in passes, which progressive rendering and checkpoints need.
+    pub fn supports_progressive(&self) -> bool {
+        !matches!(self, Self::Metropolis { .. })
+    }
+}
+
+/// Radiance split by the light group it comes from, indexed like `World::light_groups`.
+/// Groups past the end have none, so light from the default group alone takes a single
+/// entry.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Radiance {
+    pub groups: Vec<DVec3>,
+}
+
+impl Radiance {
+    pub fn new(group: usize, color: DVec3) -> Self {
+        let mut radiance = Self::default();
+        radiance.add(group, color);
+        radiance
+    }
+
+    pub fn add(&mut self, group: usize, color: DVec3) {
+        if color == DVec3::ZERO {
+            return;
+        }
+        if self.groups.len() <= group {
+            self.groups.resize(group + 1, DVec3::ZERO);
+        }
+        self.groups[group] += color;
+    }
+
+    pub fn group(&self, group: usize) -> DVec3 {
+        self.groups.get(group).copied().unwrap_or(DVec3::ZERO)
+    }
+
+    /// Radiance of all groups together.
+    pub fn total(&self) -> DVec3 {
+        self.groups.iter().sum()
+    }
+}
+
+// light that doesn't come from any emitter, e.g. of the debug modes, counts as the default
+// group's
+impl From<DVec3> for Radiance {
+    fn from(color: DVec3) -> Self {
+        Self::new(0, color)
+    }
+}
+
+impl AddAssign for Radiance {
+    fn add_assign(&mut self, other: Self) {
+        for (group, color) in other.groups.into_iter().enumerate() {
+            self.add(group, color);
+        }
+    }
+}
+
+impl Mul<DVec3> for Radiance {
+    type Output = Self;
+
+    fn mul(mut self, scale: DVec3) -> Self {
+        self.groups.iter_mut().for_each(|color| *color *= scale);
+        self
+    }
+}
+
+impl Mul<Radiance> for DVec3 {
+    type Output = Radiance;
+
+    fn mul(self, radiance: Radiance) -> Radiance {
+        radiance * self
+    }
+}
+
+impl Mul<f64> for Radiance {
+    type Output = Self;
+
+    fn mul(self, scale: f64) -> Self {
+        self * DVec3::splat(scale)
+    }
+}
+
+/// Randomly ends paths with a probability based on their `throughput`, reweighting the
+/// surviving ones to stay unbiased. Returns whether the path survives.
+pub fn russian_roulette(throughput: &mut DVec3) -> bool {
+    let survival = throughput.max_element().min(0.95);
+    if sampler::f64() >= survival {
+        return false;
+    }
+    *throughput /= survival;
+    true
+}
+
+/// A ray leaving a point on an emitter, see `sample_emission`.
+pub struct Emission<'a> {
+    pub hit_record: HitRecord<'a>,
+    pub ray: Ray,
+    // radiance emitted along `ray`
+    pub le: DVec3,
+    // between `ray` and the side of the emitter it leaves from
+    pub cosine: f64,
+    pub pdf_pos: f64,
+    pub pdf_dir: f64,
+}
+
+/// Picks an emitter uniformly, a point on it and a cosine weighted direction from a random
+/// side, for paths that start at the lights.
+pub fn sample_emission<'a>(world: &World<'a>) -> Option<Emission<'a>> {
+    let (hit_record, pdf_pos) = world.sample_light()?;
+    let normal = if sampler::bool() {
+        hit_record.normal
+    } else {
+        -hit_record.normal
+    };
+    let mut dir = normal + DVec3::random();
+    if dir.near_zero() {
+        dir = normal;
+    }
+    let dir = dir.normalize();
+    let cosine = DVec3::dot(dir, normal);
+    let le = hit_record.material.emit(&hit_record, dir);
+    let ray = Ray {
+        origin: hit_record.pos,
+        dir,
+        differential: None,
+    };
+    Some(Emission {
+        hit_record,
+        ray,
+        le,
+        cosine,
+        pdf_pos,
+        pdf_dir: cosine / PI / 2.0,
+    })
+}
+
+/// Light from one randomly picked light source arriving at `hit_record` and reflected
+/// towards `wo`, for next-event estimation. With `mis` the light is weighted against the
+/// path continuing by BSDF sampling and finding the same light by chance.
+pub fn sample_light_sources(
+    world: &World,
+    hit_record: &HitRecord,
+    wo: DVec3,
+    mis: bool,
+) -> Radiance {
+    match light_source_sample(world, hit_record, wo, mis) {
+        Some((group, light, true)) => Radiance::new(group, light),
+        _ => Radiance::default(),
+    }
+}
+
+// `sample_light_sources` without the shadow, with the light group and whether the light is
+// visible
+fn light_source_sample(
+    world: &World,
+    hit_record: &HitRecord,
+    wo: DVec3,
+    mis: bool,
+) -> Option<(usize, DVec3, bool)> {
+    let count = world.light_sources.len();
+    if count == 0 || hit_record.material.is_specular() {
+        return None;
+    }
+    let light = world.light_sources[sampler::usize(count)];
+    if !World::illuminates(light.light_group(), Some(hit_record.object)) {
+        return None;
+    }
+    let sample = light.sample(hit_record.pos)?;
+
+    let f = hit_record.material.bsdf(hit_record, wo, sample.wi);
+    if f == DVec3::ZERO {
+        return None;
+    }
+    let visible = world.unoccluded(hit_record.pos, sample.wi, sample.distance);
+    let cosine = DVec3::dot(sample.wi, hit_record.normal).abs();
+
+    let pdf_light = sample.pdf / count as f64;
+    let weight = if mis && pdf_light > 0.0 {
+        let pdf_bsdf = hit_record.material.pdf(hit_record, wo, sample.wi);
+        pdf_light / (pdf_light + pdf_bsdf)
+    } else {
+        1.0
+    };
+    Some((
+        world.light_group_index(light.light_group()),
+        f * sample.li * cosine * count as f64 * weight,
+        visible,
+    ))
+}
+
+/// Light from one emitter of the scene geometry, picked by `World::sample_emitter`,
+/// arriving at `hit_record` and reflected towards `wo`. Weighted against the path
+/// continuing by BSDF sampling and hitting the same emitter, see `emitter_weight`.
+pub fn sample_emitters(world: &World, hit_record: &HitRecord, wo: DVec3) -> Radiance {
+    match emitter_sample(world, hit_record, wo) {
+        Some((group, light, true)) => Radiance::new(group, light),
+        _ => Radiance::default(),
+    }
+}
+
+// `sample_emitters` without the shadow, with the light group and whether the emitter is
+// visible
+fn emitter_sample(
+    world: &World,
+    hit_record: &HitRecord,
+    wo: DVec3,
+) -> Option<(usize, DVec3, bool)> {
+    if hit_record.material.is_specular() {
+        return None;
+    }
+    let normal = hit_record.facing_normal();
+    let (light, pdf_area) = world.sample_emitter(hit_record.pos, Some(normal))?;
+    if !World::illuminates(light.material.light_group(), Some(hit_record.object)) {
+        return None;
+    }
+
+    let to_light = light.pos - hit_record.pos;
+    let dist_squared = to_light.length_squared();
+    let wi = to_light / dist_squared.sqrt();
+    let cos_light = DVec3::dot(light.normal, wi).abs();
+    let f = hit_record.material.bsdf(hit_record, wo, wi);
+    if cos_light == 0.0 || f == DVec3::ZERO {
+        return None;
+    }
+    let visible = world.visible(hit_record.pos, light.pos);
+
+    // to solid angle
+    let pdf_light = pdf_area * dist_squared / cos_light;
+    let pdf_bsdf = hit_record.material.pdf(hit_record, wo, wi);
+    let weight = pdf_light / (pdf_light + pdf_bsdf);
+    let cosine = DVec3::dot(wi, hit_record.normal).abs();
+    Some((
+        world.light_group_index(light.material.light_group()),
+        f * light.material.emit(&light, -wi) * cosine / pdf_light * weight,
+        visible,
+    ))
+}
+
+/// Radiance and alpha of a camera `ray`, with `li` radiance of the `integrator`. Alpha is
+/// zero where the image is meant to be composited over a photograph: on holdouts, on the
+/// background if it's `transparent`, and on shadow catchers outside of shadows. Shadow
+/// catchers only add the light that other objects reflect onto them to the color.
+pub fn camera_sample(
+    integrator: &dyn Integrator,
+    ray: &Ray,
+    world: &World,
+    transparent: bool,
+) -> (Radiance, f64) {
+    // nothing to find out from the primary hit, let the integrator trace it
+    if !transparent && !world.has_mattes {
+        return (integrator.li(ray, world), 1.0);
+    }
+    let Some(x) = world.trace(ray, RayKind::Camera) else {
+        return if transparent {
+            (Radiance::default(), 0.0)
+        } else {
+            (integrator.li(ray, world), 1.0)
+        };
+    };
+    if x.material.is_holdout() {
+        return (Radiance::default(), 0.0);
+    }
+    if !x.material.is_shadow_catcher() {
+        return (integrator.li(ray, world), 1.0);
+    }
+
+    let wo = -ray.dir.normalize();
+    let alpha = shadow_opacity(world, &x, wo);
+    // only what other objects reflect, the photograph has the rest already
+    let Some((scattered, attenuation)) = x.material.scatter(ray, &x) else {
+        return (Radiance::default(), alpha);
+    };
+    let reflected = match world.trace(&scattered, scattered_kind(&x)) {
+        Some(y) if !y.material.is_shadow_catcher() => {
+            attenuation * integrator.li(&scattered, world)
+        }
+        _ => Radiance::default(),
+    };
+    (reflected, alpha)
+}
+
+/// The alpha `camera_sample` gives `ray`, without computing its radiance.
+pub fn camera_alpha(ray: &Ray, world: &World, transparent: bool) -> f64 {
+    if !transparent && !world.has_mattes {
+        return 1.0;
+    }
+    let Some(x) = world.trace(ray, RayKind::Camera) else {
+        return if transparent { 0.0 } else { 1.0 };
+    };
+    if x.material.is_holdout() {
+        0.0
+    } else if x.material.is_shadow_catcher() {
+        shadow_opacity(world, &x, -ray.dir.normalize())
+    } else {
+        1.0
+    }
+}
+
+/// How much of the direct light at `hit_record` is blocked, estimated from one light
+/// sample.
+pub fn shadow_opacity(world: &World, hit_record: &HitRecord, wo: DVec3) -> f64 {
+    let mut unshadowed = DVec3::ZERO;
+    let mut shadowed = DVec3::ZERO;
+    let samples = [
+        light_source_sample(world, hit_record, wo, false),
+        emitter_sample(world, hit_record, wo),
+    ];
+    for (_, light, visible) in samples.into_iter().flatten() {
+        unshadowed += light;
+        if visible {
+            shadowed += light;
+        }
+    }
+    let unshadowed = unshadowed.luminance();
+    if unshadowed <= 0.0 {
+        return 0.0;
+    }
+    (1.0 - shadowed.luminance() / unshadowed).clamp(0.0, 1.0)
+}
+
+/// Weight of the emission found at `hit_record` by a bounce from `prev`, given as position,
+/// normal and BSDF density, against `sample_emitters` finding it from there.
+pub fn emitter_weight(world: &World, prev: (DVec3, DVec3, f64), hit_record: &HitRecord) -> f64 {
+    let (pos, normal, pdf_bsdf) = prev;
+    let pdf_area = world.emitter_pdf(pos, Some(normal), hit_record.object);
+    let to_light = hit_record.pos - pos;
+    let dist_squared = to_light.length_squared();
+    let cos_light = DVec3::dot(hit_record.normal, to_light).abs() / dist_squared.sqrt();
+    if pdf_area == 0.0 || cos_light == 0.0 {
+        return 1.0;
+    }
+    let pdf_light = pdf_area * dist_squared / cos_light;
+    pdf_bsdf / (pdf_bsdf + pdf_light)
+}
+
+/// Radiance arriving along `ray` from outside the scene at `receiver`, the object the ray
+/// left, none for camera rays. `bsdf_pdf` is the density of the bounce that sampled the
+/// ray, none for camera rays and specular bounces, which next-event estimation can't
+/// reproduce.
+pub fn escaped_radiance(
+    world: &World,
+    ray: &Ray,
+    receiver: Option<&dyn Hittable>,
+    bsdf_pdf: Option<f64>,
+) -> Radiance {
+    let dir = ray.dir.normalize();
+    let count = world.light_sources.len() as f64;
+    let mut color = Radiance::default();
+    if World::illuminates(DEFAULT_LIGHT_GROUP, receiver) {
+        color.add(0, world.background);
+    }
+    for light in &world.light_sources {
+        let le = light.le(dir);
+        if le == DVec3::ZERO || !World::illuminates(light.light_group(), receiver) {
+            continue;
+        }
+        let weight = match bsdf_pdf {
+            Some(pdf_bsdf) => pdf_bsdf / (pdf_bsdf + light.pdf(ray.origin, dir) / count),
+            None => 1.0,
+        };
+        color.add(world.light_group_index(light.light_group()), le * weight);
+    }
+    color
+}
+
+/// Kind of the rays scattered at `hit_record`.
+pub fn scattered_kind(hit_record: &HitRecord) -> RayKind {
+    if hit_record.material.is_specular() {
+        RayKind::Specular
+    } else {
+        RayKind::Diffuse
+    }
+}
+
+/// Density of `hit_record`'s material scattering `ray` into `scattered`, none for
+/// specular materials.
+pub fn scatter_pdf(hit_record: &HitRecord, ray: &Ray, scattered: &Ray) -> Option<f64> {
+    if hit_record.material.is_specular() {
+        return None;
+    }
+    let wo = -ray.dir.normalize();
+    let wi = scattered.dir.normalize();
+    Some(hit_record.material.pdf(hit_record, wo, wi))
+}
+
+/// Unidirectional path tracer with next-event estimation. Paths stop at `max_depth`
+/// bounces, and from `rr_min_depth` bounces on they are subject to Russian roulette.
+pub struct PathTracer {
+    pub max_depth: u32,
+    pub rr_min_depth: u32,
+    // replaces the material of every surface that isn't emissive, for clay renders
+    pub override_material: Option<Lambertian>,
+}
+
+impl Integrator for PathTracer {
+    fn li(&self, ray: &Ray, world: &World) -> Radiance {
+        let mut color = Radiance::default();
+        let mut throughput = DVec3::ONE;
+        let mut ray = *ray;
+        // position, normal and BSDF density of the last bounce, unless it was specular
+        let mut prev: Option<(DVec3, DVec3, f64)> = None;
+        // the object of the last bounce, none for camera rays
+        let mut receiver: Option<&dyn Hittable> = None;
+        let mut kind = RayKind::Camera;
+
+        for depth in 0..self.max_depth {
+            let Some(x) = world.trace(&ray, kind) else {
+                let bsdf_pdf = prev.map(|(_, _, pdf)| pdf);
+                color += throughput * escaped_radiance(world, &ray, receiver, bsdf_pdf);
+                break;
+            };
+
+            let emitted = world.emitted(&x, -ray.dir.normalize(), receiver);
+            if emitted != DVec3::ZERO {
+                let weight = prev.map_or(1.0, |prev| emitter_weight(world, prev, &x));
+                let group = world.light_group_index(x.material.light_group());
+                color.add(group, throughput * emitted * weight);
+            }
+
+            let x = match &self.override_material {
+                Some(material) if !x.material.is_emissive() => HitRecord { material, ..x },
+                _ => x,
+            };
+
+            let wo = -ray.dir.normalize();
+            color += throughput * sample_light_sources(world, &x, wo, true);
+            color += throughput * sample_emitters(world, &x, wo);
+            let Some((scattered, attenuation)) = x.material.scatter(&ray, &x) else {
+                break;
+            };
+            throughput *= attenuation;
+            prev = scatter_pdf(&x, &ray, &scattered).map(|pdf| (x.pos, x.facing_normal(), pdf));
+            receiver = Some(x.object);
+            kind = scattered_kind(&x);
+
+            if depth + 1 >= self.rr_min_depth && !russian_roulette(&mut throughput) {
+                break;
+            }
+            ray = scattered;
+        }
+        color
+    }
+}
@@ -0,0 +1,550 @@
+#![allow(dead_code)]
+
+use std::f64::consts::PI;
+
+use crate::distribution::Distribution2D;
+use crate::glam_ext::DVec3Ext;
+use crate::hittable::Sphere;
+use crate::ies::IesProfile;
+use crate::sampler;
+use crate::texture::Texture;
+
+use glam::{DMat3, DMat4, DVec3};
+
+/// Light group of the emitters and light sources that aren't given one.
+pub const DEFAULT_LIGHT_GROUP: &str = "default";
+
+pub struct LightSample {
+    // normalized, from the lit point towards the light
+    pub wi: DVec3,
+    // to the sampled point on the light, infinite for lights at infinity
+    pub distance: f64,
+    // incident radiance divided by the density of sampling `wi`
+    pub li: DVec3,
+    // solid angle density of sampling `wi`, zero if rays can't hit the light
+    pub pdf: f64,
+}
+
+/// Light that isn't part of the geometry. Most can't be hit by rays, so they are only found
+/// by sampling them directly (next-event estimation). Lights at infinity like environments
+/// are seen by rays leaving the scene as well, and combined with the BSDF sampling that
+/// finds them with multiple importance sampling.
+pub trait LightSource {
+    /// Samples the light arriving at `pos`.
+    fn sample(&self, pos: DVec3) -> Option<LightSample>;
+
+    /// Radiance seen by a ray leaving the scene in direction `dir`.
+    fn le(&self, _dir: DVec3) -> DVec3 {
+        DVec3::ZERO
+    }
+
+    /// Solid angle density of `sample` picking `wi` at `pos`.
+    fn pdf(&self, _pos: DVec3, _wi: DVec3) -> f64 {
+        0.0
+    }
+
+    /// Name of the group the light's contribution is rendered into, see
+    /// `Camera::render_light_groups`.
+    fn light_group(&self) -> &str {
+        DEFAULT_LIGHT_GROUP
+    }
+}
+
+/// Shines equally in all directions. A `radius` above zero gives soft shadows.
+pub struct PointLight {
+    pub pos: DVec3,
+    // radiant intensity, per steradian
+    pub intensity: DVec3,
+    pub radius: f64,
+    // distance at which the light has faded out completely, unlimited if none
+    pub range: Option<f64>,
+    pub light_group: String,
+}
+
+impl PointLight {
+    pub fn new(pos: DVec3, intensity: DVec3, radius: f64) -> Self {
+        Self {
+            pos,
+            intensity,
+            radius,
+            range: None,
+            light_group: DEFAULT_LIGHT_GROUP.to_owned(),
+        }
+    }
+}
+
+impl LightSource for PointLight {
+    fn sample(&self, pos: DVec3) -> Option<LightSample> {
+        sample_disk(self.pos, self.radius, self.range, pos, self.intensity)
+    }
+
+    fn light_group(&self) -> &str {
+        &self.light_group
+    }
+}
+
+/// A point light restricted to a cone around `dir`, fading out between the inner and the
+/// outer cone angle (in degrees, measured from `dir`).
+pub struct SpotLight {
+    pub pos: DVec3,
+    // normalized
+    pub dir: DVec3,
+    pub intensity: DVec3,
+    pub radius: f64,
+    pub range: Option<f64>,
+    pub inner_angle: f64,
+    pub outer_angle: f64,
+    pub light_group: String,
+}
+
+impl SpotLight {
+    pub fn new(
+        pos: DVec3,
+        dir: DVec3,
+        intensity: DVec3,
+        radius: f64,
+        inner_angle: f64,
+        outer_angle: f64,
+    ) -> Self {
+        Self {
+            pos,
+            dir: dir.normalize(),
+            intensity,
+            radius,
+            range: None,
+            inner_angle,
+            outer_angle,
+            light_group: DEFAULT_LIGHT_GROUP.to_owned(),
+        }
+    }
+
+    fn falloff(&self, cos_theta: f64) -> f64 {
+        let cos_outer = self.outer_angle.to_radians().cos();
+        let cos_inner = self.inner_angle.to_radians().cos();
+        if cos_inner <= cos_outer {
+            return if cos_theta >= cos_outer { 1.0 } else { 0.0 };
+        }
+        let t = ((cos_theta - cos_outer) / (cos_inner - cos_outer)).clamp(0.0, 1.0);
+        // smoothstep
+        t * t * (3.0 - 2.0 * t)
+    }
+}
+
+impl LightSource for SpotLight {
+    fn sample(&self, pos: DVec3) -> Option<LightSample> {
+        let cos_theta = DVec3::dot((pos - self.pos).normalize(), self.dir);
+        let falloff = self.falloff(cos_theta);
+        if falloff == 0.0 {
+            return None;
+        }
+        sample_disk(
+            self.pos,
+            self.radius,
+            self.range,
+            pos,
+            self.intensity * falloff,
+        )
+    }
+
+    fn light_group(&self) -> &str {
+        &self.light_group
+    }
+}
+
+/// A point light with the angular distribution of a measured fixture. `transform` places
+/// the fixture's frame, see `IesProfile`, in the scene.
+pub struct IesLight {
+    pub pos: DVec3,
+    pub profile: IesProfile,
+    // multiplies the profile's candela values after converting them to watts per steradian
+    pub color: DVec3,
+    pub radius: f64,
+    pub range: Option<f64>,
+    // from world to fixture directions
+    to_fixture: DMat3,
+    pub light_group: String,
+}
+
+impl IesLight {
+    pub fn new(transform: DMat4, profile: IesProfile, color: DVec3, radius: f64) -> Self {
+        Self {
+            pos: transform.w_axis.truncate(),
+            profile,
+            color,
+            radius,
+            range: None,
+            to_fixture: DMat3::from_mat4(transform).inverse(),
+            light_group: DEFAULT_LIGHT_GROUP.to_owned(),
+        }
+    }
+}
+
+impl LightSource for IesLight {
+    fn sample(&self, pos: DVec3) -> Option<LightSample> {
+        let dir = (self.to_fixture * (pos - self.pos)).normalize();
+        let candela = self.profile.intensity(dir);
+        if candela == 0.0 {
+            return None;
+        }
+        // 683 lm/W, like the glTF lights
+        let intensity = self.color * candela / 683.0;
+        sample_disk(self.pos, self.radius, self.range, pos, intensity)
+    }
+
+    fn light_group(&self) -> &str {
+        &self.light_group
+    }
+}
+
+/// Light from infinitely far away, like the sun. It arrives from within a cone of
+/// `angular_diameter` degrees around `-dir`.
+pub struct DirectionalLight {
+    // normalized, the direction the light travels in
+    pub dir: DVec3,
+    // irradiance on a surface facing the light
+    pub irradiance: DVec3,
+    pub angular_diameter: f64,
+    pub light_group: String,
+}
+
+impl DirectionalLight {
+    pub fn new(dir: DVec3, irradiance: DVec3, angular_diameter: f64) -> Self {
+        Self {
+            dir: dir.normalize(),
+            irradiance,
+            angular_diameter,
+            light_group: DEFAULT_LIGHT_GROUP.to_owned(),
+        }
+    }
+}
+
+impl LightSource for DirectionalLight {
+    fn sample(&self, _pos: DVec3) -> Option<LightSample> {
+        // uniform in the cone
+        let cos_max = (self.angular_diameter.to_radians() / 2.0).cos();
+        let cos_theta = 1.0 - sampler::f64() * (1.0 - cos_max);
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * sampler::f64();
+
+        let (u, v) = self.dir.any_orthonormal_pair();
+        let wi = -self.dir * cos_theta + (u * phi.cos() + v * phi.sin()) * sin_theta;
+        Some(LightSample {
+            wi,
+            distance: f64::INFINITY,
+            li: self.irradiance,
+            pdf: 0.0,
+        })
+    }
+
+    fn light_group(&self) -> &str {
+        &self.light_group
+    }
+}
+
+/// Light from an equirectangular environment image around the scene, turned by `rotation`
+/// degrees around the y axis. Directions are importance sampled by the image brightness.
+pub struct EnvironmentLight {
+    pub texture: Texture,
+    pub rotation: f64,
+    pub intensity: f64,
+    pub light_group: String,
+    // over the image, with rows from the top
+    distribution: Distribution2D,
+    portals: Vec<PortalSampler>,
+}
+
+impl EnvironmentLight {
+    pub fn new(texture: Texture, rotation: f64, intensity: f64) -> Self {
+        let (width, height) = (texture.width, texture.height);
+        let mut func = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            // rows near the poles cover less solid angle
+            let sin_theta = (PI * (y as f64 + 0.5) / height as f64).sin();
+            for x in 0..width {
+                func.push(texture.get(x, y).luminance() * sin_theta);
+            }
+        }
+        let distribution = Distribution2D::new(&func, width as usize, height as usize);
+        Self {
+            texture,
+            rotation,
+            intensity,
+            distribution,
+            portals: Vec::new(),
+            light_group: DEFAULT_LIGHT_GROUP.to_owned(),
+        }
+    }
+
+    /// Restricts sampling to directions through `portal` for points behind it. Add portals
+    /// after setting the rotation.
+    pub fn add_portal(&mut self, portal: Portal) {
+        let sampler = PortalSampler::new(portal, |dir| self.le(dir).luminance());
+        self.portals.push(sampler);
+    }
+
+    // portals `pos` is behind, with their windows and the radiance through them, which
+    // picks one to sample
+    fn portal_windows(&self, pos: DVec3) -> Vec<(&PortalSampler, Window, f64)> {
+        self.portals
+            .iter()
+            .filter_map(|portal| {
+                let window = portal.window(pos)?;
+                let weight = portal.distribution.window_integral(window.0, window.1);
+                (weight > 0.0).then_some((portal, window, weight))
+            })
+            .collect()
+    }
+
+    /// Bakes the radiance `f` of every direction into an environment image.
+    pub fn from_fn<F>(width: u32, height: u32, f: F) -> Self
+    where
+        F: Fn(DVec3) -> DVec3,
+    {
+        let mut texture = Texture::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let s = (x as f64 + 0.5) / width as f64;
+                let t = (y as f64 + 0.5) / height as f64;
+                texture.set(x, y, f(equirect_dir((s, t))));
+            }
+        }
+        Self::new(texture, 0.0, 1.0)
+    }
+
+    fn rotation(&self) -> DMat3 {
+        DMat3::from_rotation_y(self.rotation.to_radians())
+    }
+
+    // position in the image, in [0, 1) from the upper left corner
+    fn image_pos(&self, dir: DVec3) -> (f64, f64) {
+        let dir = self.rotation().transpose() * dir.normalize();
+        let uv = Sphere::get_uv(dir);
+        (uv.x, 1.0 - uv.y)
+    }
+
+    fn image_dir(&self, pos: (f64, f64)) -> DVec3 {
+        self.rotation() * equirect_dir(pos)
+    }
+}
+
+// Direction at a position in an equirectangular image, the inverse of `Sphere::get_uv`
+// with v flipped to image space.
+fn equirect_dir((s, t): (f64, f64)) -> DVec3 {
+    let theta = PI * (1.0 - t);
+    let phi = 2.0 * PI * s - PI;
+    DVec3::new(
+        theta.sin() * phi.cos(),
+        -theta.cos(),
+        -theta.sin() * phi.sin(),
+    )
+}
+
+impl LightSource for EnvironmentLight {
+    fn sample(&self, pos: DVec3) -> Option<LightSample> {
+        let windows = self.portal_windows(pos);
+        if !windows.is_empty() {
+            let total: f64 = windows.iter().map(|(_, _, weight)| weight).sum();
+            let mut target = sampler::f64() * total;
+            let (portal, window, _) = windows
+                .iter()
+                .find(|(_, _, weight)| {
+                    target -= weight;
+                    target < 0.0
+                })
+                .unwrap_or(windows.last().unwrap());
+            let (uv, _) = portal.distribution.sample_window(
+                (sampler::f64(), sampler::f64()),
+                window.0,
+                window.1,
+            )?;
+            let (wi, _) = portal.dir(uv);
+            // other portals may open up the same direction
+            let pdf = self.pdf(pos, wi);
+            if pdf == 0.0 {
+                return None;
+            }
+            return Some(LightSample {
+                wi,
+                distance: f64::INFINITY,
+                li: self.le(wi) / pdf,
+                pdf,
+            });
+        }
+
+        let (pos, pdf) = self.distribution.sample((sampler::f64(), sampler::f64()));
+        let sin_theta = (PI * (1.0 - pos.1)).sin();
+        if pdf == 0.0 || sin_theta <= 0.0 {
+            return None;
+        }
+        // from the image area to solid angle
+        let pdf = pdf / (2.0 * PI * PI * sin_theta);
+        let wi = self.image_dir(pos);
+        Some(LightSample {
+            wi,
+            distance: f64::INFINITY,
+            li: self.le(wi) / pdf,
+            pdf,
+        })
+    }
+
+    fn le(&self, dir: DVec3) -> DVec3 {
+        let (s, t) = self.image_pos(dir);
+        let x = ((s * self.texture.width as f64) as u32).min(self.texture.width - 1);
+        let y = ((t * self.texture.height as f64) as u32).min(self.texture.height - 1);
+        self.texture.get(x, y) * self.intensity
+    }
+
+    fn pdf(&self, pos: DVec3, wi: DVec3) -> f64 {
+        let windows = self.portal_windows(pos);
+        if !windows.is_empty() {
+            let total: f64 = windows.iter().map(|(_, _, weight)| weight).sum();
+            return windows
+                .iter()
+                .map(|(portal, window, weight)| {
+                    let Some(uv) = portal.uv(wi) else {
+                        return 0.0;
+                    };
+                    let (_, jacobian) = portal.dir(uv);
+                    let pdf = portal.distribution.pdf_window(uv, window.0, window.1);
+                    weight / total * pdf / jacobian
+                })
+                .sum();
+        }
+
+        let (s, t) = self.image_pos(wi);
+        let sin_theta = (PI * (1.0 - t)).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        self.distribution.pdf((s, t)) / (2.0 * PI * PI * sin_theta)
+    }
+
+    fn light_group(&self) -> &str {
+        &self.light_group
+    }
+}
+
+/// A rectangular opening, like a window, that environment light enters the interior
+/// through. `edge1` × `edge2` points out of the interior.
+#[derive(Debug, Clone, Copy)]
+pub struct Portal {
+    pub corner: DVec3,
+    pub edge1: DVec3,
+    pub edge2: DVec3,
+}
+
+// ranges of both coordinates of a `PortalSampler`
+type Window = ((f64, f64), (f64, f64));
+
+const PORTAL_RESOLUTION: usize = 128;
+
+// Environment directions out of a portal, in coordinates given by their angles to the
+// portal's normal along either edge. Seen from any point, the directions through the
+// rectangle are a rectangle in these coordinates as well, a window of the one distribution
+// baked per portal.
+struct PortalSampler {
+    portal: Portal,
+    // columns along the edges and the outwards normal
+    frame: DMat3,
+    distribution: Distribution2D,
+}
+
+impl PortalSampler {
+    fn new<F>(portal: Portal, luminance: F) -> Self
+    where
+        F: Fn(DVec3) -> f64,
+    {
+        let x = portal.edge1.normalize();
+        let z = DVec3::cross(portal.edge1, portal.edge2).normalize();
+        let frame = DMat3::from_cols(x, DVec3::cross(z, x), z);
+        let mut sampler = Self {
+            portal,
+            frame,
+            distribution: Distribution2D::new(&[0.0], 1, 1),
+        };
+
+        let n = PORTAL_RESOLUTION;
+        let mut func = Vec::with_capacity(n * n);
+        for y in 0..n {
+            for x in 0..n {
+                let uv = ((x as f64 + 0.5) / n as f64, (y as f64 + 0.5) / n as f64);
+                let (dir, jacobian) = sampler.dir(uv);
+                func.push(luminance(dir) * jacobian);
+            }
+        }
+        sampler.distribution = Distribution2D::new(&func, n, n);
+        sampler
+    }
+
+    // direction at `uv` and the solid angle per unit area of `uv` there
+    fn dir(&self, (u, v): (f64, f64)) -> (DVec3, f64) {
+        let x = (PI * (u - 0.5)).tan();
+        let y = (PI * (v - 0.5)).tan();
+        let length = (1.0 + x * x + y * y).sqrt();
+        let dir = self.frame * DVec3::new(x, y, 1.0) / length;
+        (
+            dir,
+            PI * PI * (1.0 + x * x) * (1.0 + y * y) / length.powi(3),
+        )
+    }
+
+    fn uv(&self, dir: DVec3) -> Option<(f64, f64)> {
+        let local = self.frame.transpose() * dir;
+        if local.z <= 0.0 {
+            return None;
+        }
+        let u = (local.x / local.z).atan() / PI + 0.5;
+        let v = (local.y / local.z).atan() / PI + 0.5;
+        Some((u, v))
+    }
+
+    // the directions through the portal from `pos`, none if it isn't behind the portal
+    fn window(&self, pos: DVec3) -> Option<Window> {
+        let to_corner = self.frame.transpose() * (self.portal.corner - pos);
+        if to_corner.z <= 0.0 {
+            return None;
+        }
+        let (width, height) = (self.portal.edge1.length(), self.portal.edge2.length());
+        let range = |from: f64, size: f64| {
+            let a = (from / to_corner.z).atan() / PI + 0.5;
+            let b = ((from + size) / to_corner.z).atan() / PI + 0.5;
+            (a.min(b), a.max(b))
+        };
+        Some((range(to_corner.x, width), range(to_corner.y, height)))
+    }
+}
+
+// Picks a point on the disk of `radius` around `center` facing `pos`, which looks the
+// same as a sphere from there, and treats it as a point emitting `intensity`.
+fn sample_disk(
+    center: DVec3,
+    radius: f64,
+    range: Option<f64>,
+    pos: DVec3,
+    intensity: DVec3,
+) -> Option<LightSample> {
+    let to_center = center - pos;
+    let (u, v) = to_center.normalize().any_orthonormal_pair();
+    let r = radius * sampler::f64().sqrt();
+    let phi = 2.0 * PI * sampler::f64();
+    let to_light = to_center + (u * phi.cos() + v * phi.sin()) * r;
+
+    let dist_squared = to_light.length_squared();
+    if dist_squared == 0.0 {
+        return None;
+    }
+    let distance = dist_squared.sqrt();
+    // smooth window to zero at the range, as recommended by KHR_lights_punctual
+    let window = range.map_or(1.0, |range| {
+        (1.0 - (distance / range).powi(4)).clamp(0.0, 1.0).powi(2)
+    });
+    if window == 0.0 {
+        return None;
+    }
+    Some(LightSample {
+        wi: to_light / distance,
+        distance,
+        li: intensity * window / dist_squared,
+        pdf: 0.0,
+    })
+}
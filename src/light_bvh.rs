@@ -0,0 +1,338 @@
+use std::f64::consts::PI;
+
+use crate::hittable::Hittable;
+use crate::sampler;
+
+use glam::DVec3;
+
+/// Where, how strongly and in which directions a group of emitters shines, for estimating
+/// how much light they contribute to a point (after Conty Estevez and Kulla).
+#[derive(Debug, Clone, Copy)]
+pub struct LightBounds {
+    pub min: DVec3,
+    pub max: DVec3,
+    pub power: f64,
+    // the normals of the emitters lie within `cos_theta_o` of `axis`
+    pub axis: DVec3,
+    pub cos_theta_o: f64,
+    // and they emit up to `cos_theta_e` beyond their normals
+    pub cos_theta_e: f64,
+}
+
+impl LightBounds {
+    fn union(&self, other: &Self) -> Self {
+        if self.power == 0.0 {
+            return *other;
+        }
+        if other.power == 0.0 {
+            return *self;
+        }
+        let (axis, cos_theta_o) =
+            Self::union_cones(self.axis, self.cos_theta_o, other.axis, other.cos_theta_o);
+        Self {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+            power: self.power + other.power,
+            axis,
+            cos_theta_o,
+            cos_theta_e: self.cos_theta_e.min(other.cos_theta_e),
+        }
+    }
+
+    // smallest cone around both cones
+    fn union_cones(a: DVec3, cos_a: f64, b: DVec3, cos_b: f64) -> (DVec3, f64) {
+        let theta_a = cos_a.clamp(-1.0, 1.0).acos();
+        let theta_b = cos_b.clamp(-1.0, 1.0).acos();
+        let theta_d = DVec3::dot(a, b).clamp(-1.0, 1.0).acos();
+        if (theta_d + theta_b).min(PI) <= theta_a {
+            return (a, cos_a);
+        }
+        if (theta_d + theta_a).min(PI) <= theta_b {
+            return (b, cos_b);
+        }
+
+        let theta_o = (theta_a + theta_d + theta_b) / 2.0;
+        if theta_o >= PI {
+            return (a, -1.0);
+        }
+        // rotate `a` towards `b` so the new cone just covers both
+        let theta_r = theta_o - theta_a;
+        let w = DVec3::cross(a, b);
+        if w.length_squared() == 0.0 {
+            return (a, -1.0);
+        }
+        let axis = rotate(a, w.normalize(), theta_r);
+        (axis, theta_o.cos())
+    }
+
+    /// Estimate of the light reaching `pos` on a surface with `normal`, if any.
+    pub fn importance(&self, pos: DVec3, normal: Option<DVec3>) -> f64 {
+        let center = (self.min + self.max) / 2.0;
+        // don't let points close to the bounds blow the estimate up
+        let dist_squared = pos
+            .distance_squared(center)
+            .max(self.min.distance(self.max) / 2.0);
+
+        // angle between the axis and the direction to `pos`
+        let cos_theta_w = DVec3::dot((pos - center).normalize_or_zero(), self.axis);
+        let sin_theta_w = safe_sqrt(1.0 - cos_theta_w * cos_theta_w);
+
+        // half the angle the bounds cover seen from `pos`
+        let radius_squared = self.min.distance_squared(self.max) / 4.0;
+        let cos_theta_b = if pos.distance_squared(center) < radius_squared {
+            -1.0
+        } else {
+            safe_sqrt(1.0 - radius_squared / pos.distance_squared(center))
+        };
+        let sin_theta_b = safe_sqrt(1.0 - cos_theta_b * cos_theta_b);
+
+        // smallest possible angle between an emitter normal and the direction to `pos`
+        let sin_theta_o = safe_sqrt(1.0 - self.cos_theta_o * self.cos_theta_o);
+        let cos_theta_x = cos_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, self.cos_theta_o);
+        let sin_theta_x = sin_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, self.cos_theta_o);
+        let cos_theta_p = cos_sub_clamped(sin_theta_x, cos_theta_x, sin_theta_b, cos_theta_b);
+        if cos_theta_p <= self.cos_theta_e {
+            return 0.0;
+        }
+
+        let mut importance = self.power * cos_theta_p / dist_squared;
+        if let Some(normal) = normal {
+            let cos_theta_i = DVec3::dot((center - pos).normalize_or_zero(), normal).abs();
+            let sin_theta_i = safe_sqrt(1.0 - cos_theta_i * cos_theta_i);
+            importance *= cos_sub_clamped(sin_theta_i, cos_theta_i, sin_theta_b, cos_theta_b);
+        }
+        importance.max(0.0)
+    }
+}
+
+fn safe_sqrt(x: f64) -> f64 {
+    x.max(0.0).sqrt()
+}
+
+// cos(max(0, a - b))
+fn cos_sub_clamped(sin_a: f64, cos_a: f64, sin_b: f64, cos_b: f64) -> f64 {
+    if cos_a > cos_b {
+        return 1.0;
+    }
+    cos_a * cos_b + sin_a * sin_b
+}
+
+// sin(max(0, a - b))
+fn sin_sub_clamped(sin_a: f64, cos_a: f64, sin_b: f64, cos_b: f64) -> f64 {
+    if cos_a > cos_b {
+        return 0.0;
+    }
+    sin_a * cos_b - cos_a * sin_b
+}
+
+// Rodrigues' rotation of `v` around the normalized `axis`
+fn rotate(v: DVec3, axis: DVec3, angle: f64) -> DVec3 {
+    let (sin, cos) = angle.sin_cos();
+    v * cos + DVec3::cross(axis, v) * sin + axis * DVec3::dot(axis, v) * (1.0 - cos)
+}
+
+enum Node {
+    Leaf { light: usize },
+    Interior { children: [usize; 2] },
+}
+
+/// Hierarchy over the emitters of the scene for picking one in proportion to how much
+/// light it likely contributes to a given point, instead of uniformly. Traversal picks a
+/// child by the `LightBounds::importance` of both until it reaches a single emitter.
+pub struct LightBvh {
+    nodes: Vec<Node>,
+    bounds: Vec<LightBounds>,
+    // path from the root to each light, one bit per level, 1 for the second child
+    trails: Vec<(u64, u32)>,
+}
+
+impl LightBvh {
+    pub fn new(lights: &[&dyn Hittable]) -> Self {
+        let mut bvh = Self {
+            nodes: Vec::new(),
+            bounds: Vec::new(),
+            trails: vec![(0, 0); lights.len()],
+        };
+        let mut leaves: Vec<(usize, LightBounds)> = lights
+            .iter()
+            .enumerate()
+            .filter_map(|(i, light)| Some((i, light.light_bounds()?)))
+            .collect();
+        if !leaves.is_empty() {
+            bvh.build(&mut leaves, 0, 0);
+        }
+        bvh
+    }
+
+    fn build(&mut self, leaves: &mut [(usize, LightBounds)], trail: u64, depth: u32) -> usize {
+        let index = self.nodes.len();
+        if let [(light, bounds)] = *leaves {
+            self.nodes.push(Node::Leaf { light });
+            self.bounds.push(bounds);
+            self.trails[light] = (trail, depth);
+            return index;
+        }
+
+        // split at the median along the longest axis of the centers, which also keeps the
+        // tree shallow enough for the trails
+        let (min, max) = leaves.iter().fold(
+            (DVec3::INFINITY, DVec3::NEG_INFINITY),
+            |(min, max), (_, b)| {
+                let center = (b.min + b.max) / 2.0;
+                (min.min(center), max.max(center))
+            },
+        );
+        let axis = (max - min).max_position();
+        leaves.sort_unstable_by(|(_, a), (_, b)| {
+            (a.min[axis] + a.max[axis]).total_cmp(&(b.min[axis] + b.max[axis]))
+        });
+        let mid = leaves.len() / 2;
+
+        self.nodes.push(Node::Interior { children: [0, 0] });
+        self.bounds.push(leaves[0].1);
+        let (lower, upper) = leaves.split_at_mut(mid);
+        let first = self.build(lower, trail, depth + 1);
+        let second = self.build(upper, trail | (1 << depth), depth + 1);
+        self.nodes[index] = Node::Interior {
+            children: [first, second],
+        };
+        self.bounds[index] = self.bounds[first].union(&self.bounds[second]);
+        index
+    }
+
+    /// Picks a light for lighting `pos`, see `LightBounds::importance`. Returns its index
+    /// and the probability of picking it.
+    pub fn sample(&self, pos: DVec3, normal: Option<DVec3>) -> Option<(usize, f64)> {
+        if self.nodes.is_empty() || self.bounds[0].importance(pos, normal) == 0.0 {
+            return None;
+        }
+        let mut index = 0;
+        let mut pmf = 1.0;
+        loop {
+            match &self.nodes[index] {
+                Node::Leaf { light } => return Some((*light, pmf)),
+                Node::Interior { children } => {
+                    let importance =
+                        children.map(|child| self.bounds[child].importance(pos, normal));
+                    let sum = importance[0] + importance[1];
+                    if sum == 0.0 {
+                        return None;
+                    }
+                    let p = importance[0] / sum;
+                    if sampler::f64() < p {
+                        index = children[0];
+                        pmf *= p;
+                    } else {
+                        index = children[1];
+                        pmf *= 1.0 - p;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Probability of `sample` picking `light` for lighting `pos`.
+    pub fn pmf(&self, pos: DVec3, normal: Option<DVec3>, light: usize) -> f64 {
+        let (trail, depth) = self.trails[light];
+        if self.nodes.is_empty() || self.bounds[0].importance(pos, normal) == 0.0 {
+            return 0.0;
+        }
+        let mut index = 0;
+        let mut pmf = 1.0;
+        for level in 0..depth {
+            let Node::Interior { children } = &self.nodes[index] else {
+                return 0.0;
+            };
+            let importance = children.map(|child| self.bounds[child].importance(pos, normal));
+            let sum = importance[0] + importance[1];
+            if sum == 0.0 {
+                return 0.0;
+            }
+            let side = ((trail >> level) & 1) as usize;
+            pmf *= importance[side] / sum;
+            index = children[side];
+        }
+        match self.nodes[index] {
+            Node::Leaf { light: found } if found == light => pmf,
+            _ => 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hittable::{HitRecord, Hittable, Triangle};
+    use crate::material::Light;
+    use crate::world::World;
+
+    use glam::DVec3;
+
+    // mean and variance of the direct irradiance estimates at `pos`
+    fn estimate<'a>(
+        samples: u32,
+        pos: DVec3,
+        normal: DVec3,
+        mut sample: impl FnMut() -> Option<(HitRecord<'a>, f64)>,
+    ) -> (f64, f64) {
+        let (mut sum, mut sum_sq) = (0.0, 0.0);
+        for _ in 0..samples {
+            let value = sample().map_or(0.0, |(hit_record, pdf)| {
+                let to_light = hit_record.pos - pos;
+                let dir = to_light.normalize();
+                let le = hit_record.material.emit(&hit_record, -dir).x;
+                let cos_light = DVec3::dot(hit_record.normal, -dir).abs();
+                let cos_surface = DVec3::dot(normal, dir).max(0.0);
+                le * cos_light * cos_surface / to_light.length_squared() / pdf
+            });
+            sum += value;
+            sum_sq += value * value;
+        }
+        let mean = sum / samples as f64;
+        (mean, sum_sq / samples as f64 - mean * mean)
+    }
+
+    #[test]
+    fn bvh_sampling_lowers_variance() {
+        fastrand::seed(7);
+        // a ceiling of small emitters facing down, lit from near one corner
+        let mut light = Light::new(DVec3::ONE);
+        light.two_sided = false;
+        let triangles: Vec<Triangle> = (0..32 * 32)
+            .map(|i| {
+                let corner = DVec3::new((i % 32) as f64 - 16.0, 2.0, (i / 32) as f64 - 16.0);
+                Triangle::new_with_vertices(
+                    [
+                        corner,
+                        corner + DVec3::new(0.2, 0.0, 0.0),
+                        corner + DVec3::new(0.0, 0.0, 0.2),
+                    ],
+                    &light,
+                )
+            })
+            .collect();
+        let objects: Vec<&dyn Hittable> = triangles.iter().map(|t| t as &dyn Hittable).collect();
+        let world = World::new(&objects, &[], DVec3::ZERO);
+
+        let (pos, normal) = (DVec3::new(-14.0, 0.0, -14.0), DVec3::Y);
+        let samples = 100_000;
+        let (uniform_mean, uniform_var) = estimate(samples, pos, normal, || world.sample_light());
+        let (bvh_mean, bvh_var) = estimate(samples, pos, normal, || {
+            let (hit_record, pdf) = world.sample_emitter(pos, Some(normal))?;
+            assert!(
+                (pdf - world.emitter_pdf(pos, Some(normal), hit_record.object)).abs() <= 1e-9 * pdf
+            );
+            Some((hit_record, pdf))
+        });
+
+        // both are unbiased, so they agree up to their standard errors
+        let error = ((uniform_var + bvh_var) / samples as f64).sqrt();
+        assert!(
+            (uniform_mean - bvh_mean).abs() < 4.0 * error,
+            "uniform {uniform_mean} vs bvh {bvh_mean} ± {error}"
+        );
+        assert!(
+            bvh_var < uniform_var / 10.0,
+            "uniform variance {uniform_var} vs bvh {bvh_var}"
+        );
+    }
+}
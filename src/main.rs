@@ -1,16 +1,37 @@
+use std::path::Path;
+use std::time::Duration;
+
+use camera::Checkpointing;
 use hittable::Triangle;
 use material::Light;
 use scene::Scene;
+use texture::OutputFormat;
 
 use glam::DVec3;
 
+mod bdpt;
 mod camera;
+mod checkpoint;
+mod debug;
+mod distribution;
+mod film;
+mod filter;
 mod glam_ext;
 mod hittable;
+mod ies;
+mod integrator;
+mod light;
+mod light_bvh;
 mod material;
+mod metropolis;
+mod photon_map;
+mod procedural;
 mod ray;
+mod sampler;
 mod scene;
+mod sky;
 mod texture;
+mod world;
 
 fn main() {
     println!("Hello, world!");
@@ -39,19 +60,40 @@ fn main() {
     );
 
     scene.camera.sample_per_pixel = 400;
+    let checkpoint = Path::new("output.checkpoint");
+    if scene.camera.integrator.supports_progressive() {
+        scene.camera.checkpoint = Some(Checkpointing {
+            path: checkpoint.to_path_buf(),
+            interval: Duration::from_secs(60),
+        });
+    }
 
     let mut list = scene.ref_vec();
     list.push(&light_1);
     list.push(&light_2);
+    let lights = scene.light_ref_vec();
 
-    let data = scene.camera.render(&list);
+    // pick up where an interrupted render left off, a checkpoint of other settings is stale
+    let resumed = (scene.camera.checkpoint.is_some() && checkpoint.exists()).then(|| {
+        scene
+            .camera
+            .resume(&list, &lights, checkpoint, |_, _| {})
+            .inspect_err(|err| eprintln!("Ignoring checkpoint: {err}"))
+            .ok()
+    });
+    let film = match resumed.flatten() {
+        Some(film) => film,
+        None => scene
+            .camera
+            .render_film(&list, &lights)
+            .expect("Unable to render"),
+    };
 
-    image::save_buffer(
-        "output.png",
-        data.rgb_buffer().as_slice(),
-        data.width,
-        data.height,
-        image::ColorType::Rgb8,
-    )
-    .expect("Unable to write image data");
+    film.to_texture()
+        .save("output.png", OutputFormat::Png8)
+        .expect("Unable to write image data");
+    // the render is complete, a later run must not resume from it
+    if scene.camera.checkpoint.is_some() {
+        std::fs::remove_file(checkpoint).expect("Unable to remove checkpoint");
+    }
 }
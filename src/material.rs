@@ -2,31 +2,96 @@
 
 use crate::glam_ext::DVec3Ext;
 use crate::hittable::{Facing, HitRecord};
-use crate::ray::Ray;
-use crate::texture::Texture;
+use crate::light::DEFAULT_LIGHT_GROUP;
+use crate::ray::{Ray, RayDifferential};
+use crate::sampler;
+use crate::texture::TextureSource;
 
-use glam::{DVec2, DVec3};
+use std::f64::consts::PI;
+
+use glam::DVec3;
 
 pub trait Material {
     fn scatter(&self, ray_in: &Ray, hit_record: &HitRecord) -> Option<(Ray, DVec3)>;
-    fn emit(&self) -> DVec3 {
+
+    /// Radiance emitted from `hit_record` towards `wo`, which points away from the surface
+    /// and is normalized.
+    fn emit(&self, _hit_record: &HitRecord, _wo: DVec3) -> DVec3 {
+        DVec3::ZERO
+    }
+
+    fn is_emissive(&self) -> bool {
+        false
+    }
+
+    /// Rough average of `emit` over the surface, to sample brighter emitters more often.
+    fn average_emission(&self) -> DVec3 {
+        DVec3::ZERO
+    }
+
+    /// Whether `emit` shines on the back side as well as along the normal.
+    fn is_two_sided(&self) -> bool {
+        true
+    }
+
+    /// See `LightSource::light_group`.
+    fn light_group(&self) -> &str {
+        DEFAULT_LIGHT_GROUP
+    }
+
+    /// See `Holdout`.
+    fn is_holdout(&self) -> bool {
+        false
+    }
+
+    /// See `ShadowCatcher`.
+    fn is_shadow_catcher(&self) -> bool {
+        false
+    }
+
+    /// Whether `scatter` only picks discrete directions, in which case `bsdf` and `pdf`
+    /// can't be used to connect paths through this material.
+    fn is_specular(&self) -> bool {
+        true
+    }
+
+    /// BSDF for light arriving from `wi` and leaving towards `wo`, both pointing away from
+    /// the surface and normalized.
+    fn bsdf(&self, _hit_record: &HitRecord, _wo: DVec3, _wi: DVec3) -> DVec3 {
         DVec3::ZERO
     }
+
+    /// Solid angle density of `scatter` picking `wi` for a path leaving towards `wo`.
+    fn pdf(&self, _hit_record: &HitRecord, _wo: DVec3, _wi: DVec3) -> f64 {
+        0.0
+    }
 }
 
-pub struct Lambertian {
-    pub albedo: DVec3,
+// `normal + DVec3::random()` picks directions proportional to the cosine
+fn cosine_pdf(hit_record: &HitRecord, wo: DVec3, wi: DVec3) -> f64 {
+    let normal = hit_record.facing_normal();
+    if DVec3::dot(wo, normal) <= 0.0 {
+        return 0.0;
+    }
+    DVec3::dot(wi, normal).max(0.0) / PI
 }
 
-impl Lambertian {
-    pub fn new(albedo: DVec3) -> Self {
+/// Diffuse surface. Its albedo, like the parameters of the other materials, is any
+/// `TextureSource`, a constant by default.
+pub struct Lambertian<T = DVec3> {
+    pub albedo: T,
+}
+
+impl<T: TextureSource> Lambertian<T> {
+    pub fn new(albedo: T) -> Self {
         Self { albedo }
     }
 }
 
-impl Material for Lambertian {
+impl<T: TextureSource> Material for Lambertian<T> {
     fn scatter(&self, _ray_in: &Ray, hit_record: &HitRecord) -> Option<(Ray, DVec3)> {
-        if self.albedo.near_zero() {
+        let albedo = self.albedo.value(&hit_record.texture_context());
+        if albedo.near_zero() {
             return None;
         }
 
@@ -42,25 +107,137 @@ impl Material for Lambertian {
         let ray_out = Ray {
             origin: hit_record.pos,
             dir,
+            differential: None,
         };
-        Some((ray_out, self.albedo))
+        Some((ray_out, albedo))
+    }
+
+    fn is_specular(&self) -> bool {
+        false
+    }
+
+    fn bsdf(&self, hit_record: &HitRecord, wo: DVec3, wi: DVec3) -> DVec3 {
+        if cosine_pdf(hit_record, wo, wi) == 0.0 {
+            return DVec3::ZERO;
+        }
+        self.albedo.value(&hit_record.texture_context()) / PI
+    }
+
+    fn pdf(&self, hit_record: &HitRecord, wo: DVec3, wi: DVec3) -> f64 {
+        cosine_pdf(hit_record, wo, wi)
+    }
+}
+
+/// Cuts a hole into the image, black and transparent where the camera sees it, e.g. for an
+/// object a live-action plate covers. Absorbs all other light.
+pub struct Holdout;
+
+impl Material for Holdout {
+    fn scatter(&self, _ray_in: &Ray, _hit_record: &HitRecord) -> Option<(Ray, DVec3)> {
+        None
+    }
+
+    fn is_holdout(&self) -> bool {
+        true
+    }
+}
+
+/// Stands in for the ground of a photograph that CG objects are composited onto. The camera
+/// sees through it, except for the shadows other objects cast onto it, which go into alpha,
+/// and the light they reflect onto it, which goes into the color. All other rays see a
+/// diffuse surface of `albedo`, matching the real ground.
+pub struct ShadowCatcher<T = DVec3> {
+    pub albedo: T,
+}
+
+impl<T: TextureSource> ShadowCatcher<T> {
+    pub fn new(albedo: T) -> Self {
+        Self { albedo }
+    }
+
+    fn surface(&self) -> Lambertian<&T> {
+        Lambertian::new(&self.albedo)
+    }
+}
+
+impl<T: TextureSource> Material for ShadowCatcher<T> {
+    fn scatter(&self, ray_in: &Ray, hit_record: &HitRecord) -> Option<(Ray, DVec3)> {
+        self.surface().scatter(ray_in, hit_record)
+    }
+
+    fn is_specular(&self) -> bool {
+        false
+    }
+
+    fn bsdf(&self, hit_record: &HitRecord, wo: DVec3, wi: DVec3) -> DVec3 {
+        self.surface().bsdf(hit_record, wo, wi)
+    }
+
+    fn pdf(&self, hit_record: &HitRecord, wo: DVec3, wi: DVec3) -> f64 {
+        self.surface().pdf(hit_record, wo, wi)
+    }
+
+    fn is_shadow_catcher(&self) -> bool {
+        true
     }
 }
 
-pub struct Metal {
-    pub albedo: DVec3,
-    pub fuzziness: f64,
+// Differentials of a ray leaving a specular surface in the normalized direction `wi`,
+// mirrored or, given the ratio of the indices of refraction `eta`, refracted. The offset
+// rays bend with the surface normal, as in pbrt.
+fn specular_differential(
+    ray_in: &Ray,
+    hit_record: &HitRecord,
+    wi: DVec3,
+    eta: Option<f64>,
+) -> Option<RayDifferential> {
+    let differential = ray_in.differential?;
+    let hit = hit_record.differentials(ray_in)?;
+    let wo = -ray_in.dir.normalize();
+    let (n, dndx, dndy) = if DVec3::dot(wo, hit_record.normal) < 0.0 {
+        (-hit_record.normal, -hit.dndx, -hit.dndy)
+    } else {
+        (hit_record.normal, hit.dndx, hit.dndy)
+    };
+
+    let cos_o = DVec3::dot(wo, n);
+    let offset_dir = |dir: DVec3, dndx: DVec3| {
+        let dwo = -dir.normalize() - wo;
+        let dcos_o = DVec3::dot(dwo, n) + DVec3::dot(wo, dndx);
+        match eta {
+            None => wi - dwo + 2.0 * (cos_o * dndx + dcos_o * n),
+            Some(eta) => {
+                let cos_i = DVec3::dot(wi, n).abs();
+                let mu = eta * cos_o - cos_i;
+                let dmu = (eta - eta * eta * cos_o / cos_i) * dcos_o;
+                wi - eta * dwo + mu * dndx + dmu * n
+            }
+        }
+    };
+    Some(RayDifferential {
+        rx_origin: hit_record.pos + hit.dpdx,
+        rx_dir: offset_dir(differential.rx_dir, dndx),
+        ry_origin: hit_record.pos + hit.dpdy,
+        ry_dir: offset_dir(differential.ry_dir, dndy),
+    })
+}
+
+pub struct Metal<A = DVec3, F = f64> {
+    pub albedo: A,
+    pub fuzziness: F,
 }
 
-impl Metal {
-    pub fn new(albedo: DVec3, fuzziness: f64) -> Self {
+impl<A: TextureSource, F: TextureSource> Metal<A, F> {
+    pub fn new(albedo: A, fuzziness: F) -> Self {
         Self { albedo, fuzziness }
     }
 }
 
-impl Material for Metal {
+impl<A: TextureSource, F: TextureSource> Material for Metal<A, F> {
     fn scatter(&self, ray_in: &Ray, hit_record: &HitRecord) -> Option<(Ray, DVec3)> {
-        if self.albedo.near_zero() {
+        let ctx = hit_record.texture_context();
+        let albedo = self.albedo.value(&ctx);
+        if albedo.near_zero() {
             return None;
         }
 
@@ -68,25 +245,27 @@ impl Material for Metal {
             Facing::Front => hit_record.normal,
             Facing::Back => -hit_record.normal,
         };
-        let dir = ray_in.dir.reflect(normal).normalize() + DVec3::random() * self.fuzziness;
+        let dir =
+            ray_in.dir.reflect(normal).normalize() + DVec3::random() * self.fuzziness.scalar(&ctx);
         let ray_out = Ray {
             origin: hit_record.pos,
             dir,
+            differential: specular_differential(ray_in, hit_record, dir.normalize(), None),
         };
         if DVec3::dot(dir, normal) > 0.0 {
-            Some((ray_out, self.albedo))
+            Some((ray_out, albedo))
         } else {
             None
         }
     }
 }
 
-pub struct Dielectric {
-    refr_index: f64,
+pub struct Dielectric<T = f64> {
+    refr_index: T,
 }
 
-impl Dielectric {
-    pub fn new(refr_index: f64) -> Self {
+impl<T: TextureSource> Dielectric<T> {
+    pub fn new(refr_index: T) -> Self {
         Self { refr_index }
     }
 
@@ -98,25 +277,32 @@ impl Dielectric {
     }
 }
 
-impl Material for Dielectric {
+impl<T: TextureSource> Material for Dielectric<T> {
     fn scatter(&self, ray_in: &Ray, hit_record: &HitRecord) -> Option<(Ray, DVec3)> {
+        let refr_index = self.refr_index.scalar(&hit_record.texture_context());
         let (normal_in, ri) = match hit_record.facing {
-            Facing::Front => (hit_record.normal, 1.0 / self.refr_index),
-            Facing::Back => (-hit_record.normal, self.refr_index),
+            Facing::Front => (hit_record.normal, 1.0 / refr_index),
+            Facing::Back => (-hit_record.normal, refr_index),
         };
 
         let in_dir = ray_in.dir.normalize();
         let cosine = DVec3::dot(in_dir, hit_record.normal).abs();
 
-        let reflectance = Dielectric::reflectance_schlick(cosine, ri);
+        let reflectance = Self::reflectance_schlick(cosine, ri);
 
-        if fastrand::f64() > reflectance {
+        if sampler::f64() > reflectance {
             let refracted = in_dir.refract(normal_in, ri);
             if refracted != DVec3::ZERO {
                 return Some((
                     Ray {
                         origin: hit_record.pos,
                         dir: refracted,
+                        differential: specular_differential(
+                            ray_in,
+                            hit_record,
+                            refracted,
+                            Some(ri),
+                        ),
                     },
                     DVec3::ONE,
                 ));
@@ -127,6 +313,7 @@ impl Material for Dielectric {
             Ray {
                 origin: hit_record.pos,
                 dir: refracted,
+                differential: specular_differential(ray_in, hit_record, refracted, None),
             },
             DVec3::ONE,
         ))
@@ -134,19 +321,18 @@ impl Material for Dielectric {
 }
 
 pub struct BasicMaterial<'a> {
-    albedo: &'a Texture,
+    albedo: &'a dyn TextureSource,
 }
 
 impl<'a> BasicMaterial<'a> {
-    pub fn new(albedo: &'a Texture) -> Self {
+    pub fn new(albedo: &'a dyn TextureSource) -> Self {
         Self { albedo }
     }
 }
 
 impl Material for BasicMaterial<'_> {
     fn scatter(&self, _ray_in: &Ray, hit_record: &HitRecord) -> Option<(Ray, DVec3)> {
-        let DVec2 { x: u, y: v } = hit_record.tex_coords;
-        let albedo = self.albedo.sample(u, v);
+        let albedo = self.albedo.value(&hit_record.texture_context());
 
         if albedo.near_zero() {
             return None;
@@ -164,18 +350,50 @@ impl Material for BasicMaterial<'_> {
         let ray_out = Ray {
             origin: hit_record.pos,
             dir,
+            differential: None,
         };
         Some((ray_out, albedo))
     }
+
+    fn is_specular(&self) -> bool {
+        false
+    }
+
+    fn bsdf(&self, hit_record: &HitRecord, wo: DVec3, wi: DVec3) -> DVec3 {
+        if cosine_pdf(hit_record, wo, wi) == 0.0 {
+            return DVec3::ZERO;
+        }
+        self.albedo.value(&hit_record.texture_context()) / PI
+    }
+
+    fn pdf(&self, hit_record: &HitRecord, wo: DVec3, wi: DVec3) -> f64 {
+        cosine_pdf(hit_record, wo, wi)
+    }
+}
+
+// whether a surface emitting on one or both sides shines towards `wo`
+fn emits_towards(hit_record: &HitRecord, wo: DVec3, two_sided: bool) -> bool {
+    two_sided || DVec3::dot(hit_record.normal, wo) > 0.0
 }
 
 pub struct Light {
     pub color: DVec3,
+    pub two_sided: bool,
+    pub light_group: String,
 }
 
 impl Light {
     pub fn new(color: DVec3) -> Self {
-        Self { color }
+        Self {
+            color,
+            two_sided: true,
+            light_group: DEFAULT_LIGHT_GROUP.to_owned(),
+        }
+    }
+
+    /// Emission with the color of a black body at `kelvin`, with a luminance of `strength`.
+    pub fn blackbody(kelvin: f64, strength: f64) -> Self {
+        Self::new(blackbody(kelvin) * strength)
     }
 }
 
@@ -184,7 +402,114 @@ impl Material for Light {
         None
     }
 
-    fn emit(&self) -> DVec3 {
+    fn emit(&self, hit_record: &HitRecord, wo: DVec3) -> DVec3 {
+        if !emits_towards(hit_record, wo, self.two_sided) {
+            return DVec3::ZERO;
+        }
         self.color
     }
+
+    fn is_emissive(&self) -> bool {
+        true
+    }
+
+    fn average_emission(&self) -> DVec3 {
+        self.color
+    }
+
+    fn is_two_sided(&self) -> bool {
+        self.two_sided
+    }
+
+    fn light_group(&self) -> &str {
+        &self.light_group
+    }
+}
+
+/// Emission looked up in a texture, e.g. a screen or an emissive glTF texture.
+pub struct TexturedLight<'a> {
+    emission: &'a dyn TextureSource,
+    pub strength: f64,
+    pub two_sided: bool,
+    pub light_group: String,
+    average: DVec3,
+}
+
+impl<'a> TexturedLight<'a> {
+    pub fn new(emission: &'a dyn TextureSource, strength: f64) -> Self {
+        Self {
+            emission,
+            strength,
+            two_sided: true,
+            light_group: DEFAULT_LIGHT_GROUP.to_owned(),
+            average: emission.average(),
+        }
+    }
+}
+
+impl Material for TexturedLight<'_> {
+    fn scatter(&self, _ray_in: &Ray, _hit_record: &HitRecord) -> Option<(Ray, DVec3)> {
+        None
+    }
+
+    fn emit(&self, hit_record: &HitRecord, wo: DVec3) -> DVec3 {
+        if !emits_towards(hit_record, wo, self.two_sided) {
+            return DVec3::ZERO;
+        }
+        self.emission.value(&hit_record.texture_context()) * self.strength
+    }
+
+    fn is_emissive(&self) -> bool {
+        true
+    }
+
+    fn average_emission(&self) -> DVec3 {
+        self.average * self.strength
+    }
+
+    fn is_two_sided(&self) -> bool {
+        self.two_sided
+    }
+
+    fn light_group(&self) -> &str {
+        &self.light_group
+    }
+}
+
+/// Linear RGB color of a black body at `kelvin`, scaled to a luminance of one. Integrates
+/// Planck's law against the CIE 1931 color matching functions, in the analytic fit of
+/// Wyman, Sloan and Shirley.
+pub fn blackbody(kelvin: f64) -> DVec3 {
+    // piecewise gaussian
+    let g = |lambda: f64, mu: f64, sigma_low: f64, sigma_high: f64| {
+        let sigma = if lambda < mu { sigma_low } else { sigma_high };
+        (-0.5 * ((lambda - mu) / sigma).powi(2)).exp()
+    };
+
+    let mut xyz = DVec3::ZERO;
+    for i in 0..=80 {
+        let lambda = 380.0 + i as f64 * 5.0;
+        // the constants don't matter after normalizing
+        let planck =
+            (lambda * 1e-9).powi(-5) / ((1.4388e-2 / (lambda * 1e-9 * kelvin)).exp() - 1.0);
+        let cmf = DVec3::new(
+            1.056 * g(lambda, 599.8, 37.9, 31.0) + 0.362 * g(lambda, 442.0, 16.0, 26.7)
+                - 0.065 * g(lambda, 501.1, 20.4, 26.2),
+            0.821 * g(lambda, 568.8, 46.9, 40.5) + 0.286 * g(lambda, 530.9, 16.3, 31.1),
+            1.217 * g(lambda, 437.0, 11.8, 36.0) + 0.681 * g(lambda, 459.0, 26.0, 13.8),
+        );
+        xyz += cmf * planck;
+    }
+    if xyz.y <= 0.0 {
+        return DVec3::ZERO;
+    }
+    let xyz = xyz / xyz.y;
+
+    // XYZ to linear sRGB
+    DVec3::new(
+        3.2404542 * xyz.x - 1.5371385 * xyz.y - 0.4985314 * xyz.z,
+        -0.9692660 * xyz.x + 1.8760108 * xyz.y + 0.0415560 * xyz.z,
+        0.0556434 * xyz.x - 0.2040259 * xyz.y + 1.0572252 * xyz.z,
+    )
+    .max(DVec3::ZERO)
 }
@@ -0,0 +1,126 @@
+use crate::film::Film;
+use crate::glam_ext::DVec3Ext;
+use crate::integrator::{PathTracer, camera_sample};
+use crate::ray::Ray;
+use crate::sampler::{self, PrimarySamples, Sampler};
+use crate::world::World;
+
+use fastrand::Rng;
+use pbr::ProgressBar;
+
+/// Primary sample space Metropolis light transport (Kelemen et al.). Paths are traced by the
+/// path tracer, but its random numbers come from Markov chains that mutate them, so once a
+/// chain has found a bright path it keeps exploring the ones close to it. The chains are
+/// distributed over the image in proportion to brightness and splatted into the film.
+pub struct Metropolis {
+    pub max_depth: u32,
+    pub rr_min_depth: u32,
+    // number of independent samples estimating the overall image brightness
+    pub bootstrap: u32,
+    pub chains: u32,
+    pub large_step_probability: f64,
+    // standard deviation of the small step mutations
+    pub sigma: f64,
+    pub seed: u64,
+    // see `Camera::transparent`
+    pub transparent: bool,
+}
+
+impl Metropolis {
+    /// Splats `mutations` samples into `film`. `camera_ray` maps a position on the film, in
+    /// pixels from the upper left corner, to the ray through it. The splats carry no alpha,
+    /// the caller has to add it to the film separately, see `camera_alpha`.
+    pub fn render<F>(&self, world: &World, film: &mut Film, mutations: u64, camera_ray: F)
+    where
+        F: Fn(f64, f64) -> Ray,
+    {
+        let path_tracer = PathTracer {
+            max_depth: self.max_depth,
+            rr_min_depth: self.rr_min_depth,
+            override_material: None,
+        };
+        let (width, height) = (film.width as f64, film.height as f64);
+        let radiance = |primary: &mut PrimarySamples| {
+            primary.evaluate(|| {
+                let pos = (sampler::f64() * width, sampler::f64() * height);
+                let ray = camera_ray(pos.0, pos.1);
+                let (color, _) = camera_sample(&path_tracer, &ray, world, self.transparent);
+                (pos, color)
+            })
+        };
+        let chain_seed = |index: u32| Sampler::mix(self.seed ^ Sampler::mix(index as u64));
+
+        // the first sample of every bootstrap chain is an ordinary path tracing sample,
+        // their average brightness normalizes the image
+        let weights: Vec<f64> = (0..self.bootstrap)
+            .map(|i| {
+                let mut primary =
+                    PrimarySamples::new(chain_seed(i), self.sigma, self.large_step_probability);
+                radiance(&mut primary).1.total().luminance()
+            })
+            .collect();
+        let weight_sum: f64 = weights.iter().sum();
+        if weight_sum <= 0.0 {
+            return;
+        }
+        film.splat_scale =
+            weight_sum / self.bootstrap as f64 * film.pixels().len() as f64 / mutations as f64;
+
+        // at least one chain, or there would be nothing to spread the mutations over
+        let chains = self.chains.max(1);
+        let mut pb = ProgressBar::new(chains as u64);
+        pb.show_counter = false;
+        pb.show_speed = false;
+        pb.message("Rendering: ");
+        pb.format("[#>-]");
+
+        let mut rng = Rng::with_seed(self.seed);
+        let chain_mutations = mutations / chains as u64;
+        for _ in 0..chains {
+            // start from a bootstrap sample picked in proportion to its brightness, which
+            // avoids start-up bias
+            let mut target = rng.f64() * weight_sum;
+            let start = weights
+                .iter()
+                .position(|weight| {
+                    target -= weight;
+                    target < 0.0
+                })
+                .unwrap_or_else(|| weights.iter().rposition(|weight| *weight > 0.0).unwrap());
+
+            let mut primary = PrimarySamples::new(
+                chain_seed(start as u32),
+                self.sigma,
+                self.large_step_probability,
+            );
+            let (mut pos, mut color) = radiance(&mut primary);
+            let mut lum = color.total().luminance();
+
+            for _ in 0..chain_mutations {
+                primary.start_iteration();
+                let (proposed_pos, proposed_color) = radiance(&mut primary);
+                let proposed_lum = proposed_color.total().luminance();
+                let accept = (proposed_lum / lum).min(1.0);
+
+                // splat both states weighted by their chance of being next, instead of only
+                // the one that is picked
+                if accept > 0.0 {
+                    film.add_splat(
+                        proposed_pos,
+                        &(proposed_color.clone() * (accept / proposed_lum)),
+                    );
+                }
+                film.add_splat(pos, &(color.clone() * ((1.0 - accept) / lum)));
+
+                if primary.uniform() < accept {
+                    (pos, color, lum) = (proposed_pos, proposed_color, proposed_lum);
+                    primary.accept();
+                } else {
+                    primary.reject();
+                }
+            }
+            pb.inc();
+        }
+        pb.finish();
+    }
+}
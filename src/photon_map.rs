@@ -0,0 +1,248 @@
+use std::f64::consts::PI;
+
+use crate::hittable::{HitRecord, Hittable};
+use crate::integrator::{
+    Emission, Integrator, Radiance, escaped_radiance, russian_roulette, sample_emission,
+    sample_light_sources, scattered_kind,
+};
+use crate::ray::{Ray, RayKind};
+use crate::world::World;
+
+use glam::DVec3;
+
+pub struct Photon {
+    pub pos: DVec3,
+    // normalized, pointing back to where the photon came from
+    pub dir: DVec3,
+    pub power: DVec3,
+    // index of the light group of the emitter it left, see `World::light_groups`
+    pub group: usize,
+}
+
+/// Photons in a kd-tree, stored implicitly: the median of every range is the node, the
+/// lower and upper halves are its subtrees.
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+    // split axis of the node at the same index
+    axes: Vec<u8>,
+}
+
+impl PhotonMap {
+    pub fn new(mut photons: Vec<Photon>) -> Self {
+        let mut axes = vec![0; photons.len()];
+        Self::build(&mut photons, &mut axes);
+        Self { photons, axes }
+    }
+
+    fn build(photons: &mut [Photon], axes: &mut [u8]) {
+        if photons.is_empty() {
+            return;
+        }
+
+        let (min, max) = photons.iter().fold(
+            (DVec3::INFINITY, DVec3::NEG_INFINITY),
+            |(min, max), photon| (min.min(photon.pos), max.max(photon.pos)),
+        );
+        let extent = max - min;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z {
+            0
+        } else if extent.y >= extent.z {
+            1
+        } else {
+            2
+        };
+
+        let mid = photons.len() / 2;
+        photons.select_nth_unstable_by(mid, |a, b| a.pos[axis].total_cmp(&b.pos[axis]));
+        axes[mid] = axis as u8;
+
+        let (photons_lower, photons_upper) = photons.split_at_mut(mid);
+        let (axes_lower, axes_upper) = axes.split_at_mut(mid);
+        Self::build(photons_lower, axes_lower);
+        Self::build(&mut photons_upper[1..], &mut axes_upper[1..]);
+    }
+
+    /// Calls `f` for every photon within `radius` of `pos`.
+    pub fn for_each_near<F>(&self, pos: DVec3, radius: f64, mut f: F)
+    where
+        F: FnMut(&Photon),
+    {
+        Self::search(&self.photons, &self.axes, pos, radius, &mut f);
+    }
+
+    fn search<F>(photons: &[Photon], axes: &[u8], pos: DVec3, radius: f64, f: &mut F)
+    where
+        F: FnMut(&Photon),
+    {
+        if photons.is_empty() {
+            return;
+        }
+
+        let mid = photons.len() / 2;
+        let photon = &photons[mid];
+        if photon.pos.distance_squared(pos) <= radius * radius {
+            f(photon);
+        }
+
+        let axis = axes[mid] as usize;
+        let d = pos[axis] - photon.pos[axis];
+        if d <= radius {
+            Self::search(&photons[..mid], &axes[..mid], pos, radius, f);
+        }
+        if d >= -radius {
+            Self::search(&photons[mid + 1..], &axes[mid + 1..], pos, radius, f);
+        }
+    }
+}
+
+/// Photon mapping. Every iteration, one sample per pixel, traces `photons` photons from the
+/// emitters and stores them where they land on non-specular surfaces. Camera paths follow specular bounces to the
+/// first non-specular surface, where the radiance is estimated from the photons within the
+/// search radius, so caustics through glass are resolved as well as direct light.
+///
+/// Photons are only traced from emissive geometry, so light sources only contribute their
+/// direct light, through next-event estimation at the estimate.
+///
+/// The radius shrinks with every iteration as in progressive photon mapping, with `alpha`
+/// controlling how fast, so the estimate converges as more samples are taken. Each sample
+/// of a pixel sees a different photon map, so its noise averages out as well.
+pub struct PhotonMapper {
+    pub max_depth: u32,
+    pub rr_min_depth: u32,
+    pub photons: u32,
+    pub radius: f64,
+    pub alpha: f64,
+    map: PhotonMap,
+    iteration_radius: f64,
+}
+
+impl PhotonMapper {
+    pub fn new(max_depth: u32, rr_min_depth: u32, photons: u32, radius: f64, alpha: f64) -> Self {
+        Self {
+            max_depth,
+            rr_min_depth,
+            photons,
+            radius,
+            alpha,
+            map: PhotonMap::new(Vec::new()),
+            iteration_radius: radius,
+        }
+    }
+
+    fn trace_photons(&self, world: &World) -> Vec<Photon> {
+        let mut photons = Vec::new();
+
+        for _ in 0..self.photons {
+            let Some(Emission {
+                hit_record,
+                mut ray,
+                le,
+                cosine,
+                pdf_pos,
+                pdf_dir,
+            }) = sample_emission(world)
+            else {
+                break;
+            };
+            let mut power = le * cosine / (pdf_pos * pdf_dir * self.photons as f64);
+            let group = world.light_group_index(hit_record.material.light_group());
+            let mut kind = RayKind::Diffuse;
+
+            for depth in 0..self.max_depth {
+                let Some(x) = world.trace(&ray, kind) else {
+                    break;
+                };
+                if depth == 0
+                    && !World::illuminates(hit_record.material.light_group(), Some(x.object))
+                {
+                    break;
+                }
+
+                if !x.material.is_specular() {
+                    photons.push(Photon {
+                        pos: x.pos,
+                        dir: -ray.dir.normalize(),
+                        power,
+                        group,
+                    });
+                }
+
+                let Some((scattered, attenuation)) = x.material.scatter(&ray, &x) else {
+                    break;
+                };
+                power *= attenuation;
+
+                if depth + 1 >= self.rr_min_depth && !russian_roulette(&mut power) {
+                    break;
+                }
+                ray = scattered;
+                kind = scattered_kind(&x);
+            }
+        }
+        photons
+    }
+
+    fn estimate(&self, hit_record: &HitRecord, wo: DVec3) -> Radiance {
+        let mut sum = Radiance::default();
+        self.map
+            .for_each_near(hit_record.pos, self.iteration_radius, |photon| {
+                let f = hit_record.material.bsdf(hit_record, wo, photon.dir);
+                sum.add(photon.group, f * photon.power);
+            });
+        sum * (1.0 / (PI * self.iteration_radius * self.iteration_radius))
+    }
+}
+
+impl Integrator for PhotonMapper {
+    fn start_iteration(&mut self, world: &World, iteration: u32) {
+        // r_i^2 = r_(i-1)^2 * (i + alpha) / (i + 1)
+        let mut radius_squared = self.radius * self.radius;
+        for i in 1..=iteration {
+            radius_squared *= (i as f64 + self.alpha) / (i as f64 + 1.0);
+        }
+        self.iteration_radius = radius_squared.sqrt();
+        self.map = PhotonMap::new(self.trace_photons(world));
+    }
+
+    fn li(&self, ray: &Ray, world: &World) -> Radiance {
+        let mut color = Radiance::default();
+        let mut throughput = DVec3::ONE;
+        let mut ray = *ray;
+        let mut receiver: Option<&dyn Hittable> = None;
+        let mut kind = RayKind::Camera;
+
+        for depth in 0..self.max_depth {
+            let Some(x) = world.trace(&ray, kind) else {
+                // only reached by camera rays and specular bounces
+                color += throughput * escaped_radiance(world, &ray, receiver, None);
+                break;
+            };
+
+            let emitted = world.emitted(&x, -ray.dir.normalize(), receiver);
+            if emitted != DVec3::ZERO {
+                let group = world.light_group_index(x.material.light_group());
+                color.add(group, throughput * emitted);
+            }
+            // the photons already account for all the light arriving here
+            if !x.material.is_specular() {
+                let wo = -ray.dir.normalize();
+                color += throughput * self.estimate(&x, wo);
+                color += throughput * sample_light_sources(world, &x, wo, false);
+                break;
+            }
+
+            let Some((scattered, attenuation)) = x.material.scatter(&ray, &x) else {
+                break;
+            };
+            throughput *= attenuation;
+
+            if depth + 1 >= self.rr_min_depth && !russian_roulette(&mut throughput) {
+                break;
+            }
+            ray = scattered;
+            receiver = Some(x.object);
+            kind = RayKind::Specular;
+        }
+        color
+    }
+}
@@ -0,0 +1,468 @@
+#![allow(dead_code)]
+
+use std::f64::consts::PI;
+
+use crate::texture::{TextureContext, TextureSource};
+
+use glam::{DVec2, DVec3};
+
+/// Where a procedural pattern is evaluated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mapping {
+    // texture coordinates times the factor, at z = 0, for 2D patterns
+    Uv(f64),
+    // world space position times the factor, for solid patterns
+    Solid(f64),
+}
+
+impl Mapping {
+    fn point(&self, ctx: &TextureContext) -> DVec3 {
+        match *self {
+            Mapping::Uv(scale) => ctx.uv.extend(0.0) * scale,
+            Mapping::Solid(scale) => ctx.pos * scale,
+        }
+    }
+}
+
+/// Maps values in [0, 1] to colors, interpolating linearly between stops sorted by position.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColorRamp {
+    pub stops: Vec<(f64, DVec3)>,
+}
+
+impl ColorRamp {
+    pub fn new(stops: Vec<(f64, DVec3)>) -> Self {
+        Self { stops }
+    }
+
+    pub fn between(from: DVec3, to: DVec3) -> Self {
+        Self::new(vec![(0.0, from), (1.0, to)])
+    }
+
+    pub fn eval(&self, t: f64) -> DVec3 {
+        let i = self.stops.partition_point(|&(pos, _)| pos <= t);
+        if i == 0 {
+            return self.stops.first().map_or(DVec3::ZERO, |&(_, color)| color);
+        }
+        if i == self.stops.len() {
+            return self.stops[i - 1].1;
+        }
+        let ((p0, c0), (p1, c1)) = (self.stops[i - 1], self.stops[i]);
+        c0.lerp(c1, (t - p0) / (p1 - p0))
+    }
+}
+
+/// Alternating colors on a grid of unit cells, squares with `Mapping::Uv` and cubes with
+/// `Mapping::Solid`.
+pub struct Checkerboard {
+    pub even: DVec3,
+    pub odd: DVec3,
+    pub mapping: Mapping,
+}
+
+impl Checkerboard {
+    pub fn new(even: DVec3, odd: DVec3, mapping: Mapping) -> Self {
+        Self { even, odd, mapping }
+    }
+}
+
+impl TextureSource for Checkerboard {
+    fn value(&self, ctx: &TextureContext) -> DVec3 {
+        let cell = self.mapping.point(ctx).floor();
+        if (cell.x + cell.y + cell.z).rem_euclid(2.0) == 0.0 {
+            self.even
+        } else {
+            self.odd
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NoiseKind {
+    Perlin,
+    Simplex,
+}
+
+/// How octaves of noise are summed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fractal {
+    // fractional Brownian motion, soft clouds
+    Fbm,
+    // absolute values, billowy with sharp creases
+    Turbulence,
+}
+
+/// Gradient noise summed over `octaves`, each twice the frequency and half the amplitude
+/// of the last, mapped to colors through `ramp`.
+pub struct Noise {
+    pub kind: NoiseKind,
+    pub fractal: Fractal,
+    pub octaves: u32,
+    pub ramp: ColorRamp,
+    pub mapping: Mapping,
+}
+
+impl Noise {
+    pub fn new(kind: NoiseKind, mapping: Mapping) -> Self {
+        Self {
+            kind,
+            fractal: Fractal::Fbm,
+            octaves: 4,
+            ramp: ColorRamp::between(DVec3::ZERO, DVec3::ONE),
+            mapping,
+        }
+    }
+}
+
+impl TextureSource for Noise {
+    fn value(&self, ctx: &TextureContext) -> DVec3 {
+        let p = self.mapping.point(ctx);
+        let noise = match self.kind {
+            NoiseKind::Perlin => perlin,
+            NoiseKind::Simplex => simplex,
+        };
+        let t = match self.fractal {
+            Fractal::Fbm => fbm(noise, p, self.octaves) * 0.5 + 0.5,
+            Fractal::Turbulence => turbulence(noise, p, self.octaves),
+        };
+        self.ramp.eval(t.clamp(0.0, 1.0))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WorleyOutput {
+    // distance to the closest feature point, dark spots at the points
+    Distance,
+    // difference between the two closest, dark lines along cell borders
+    Edges,
+    // a random position on the ramp per cell, like stones or crackle glaze
+    Cells,
+}
+
+/// Cellular noise around one random feature point per unit cell, also known as Voronoi.
+pub struct Worley {
+    pub output: WorleyOutput,
+    pub ramp: ColorRamp,
+    pub mapping: Mapping,
+}
+
+impl Worley {
+    pub fn new(output: WorleyOutput, mapping: Mapping) -> Self {
+        Self {
+            output,
+            ramp: ColorRamp::between(DVec3::ZERO, DVec3::ONE),
+            mapping,
+        }
+    }
+}
+
+impl TextureSource for Worley {
+    fn value(&self, ctx: &TextureContext) -> DVec3 {
+        let (f1, f2, cell) = worley(self.mapping.point(ctx));
+        let t = match self.output {
+            WorleyOutput::Distance => f1,
+            WorleyOutput::Edges => f2 - f1,
+            WorleyOutput::Cells => unit(cell),
+        };
+        self.ramp.eval(t.clamp(0.0, 1.0))
+    }
+}
+
+/// Veins along x, bent by turbulence.
+pub struct Marble {
+    pub ramp: ColorRamp,
+    // how far the veins wander, around 5 for marble
+    pub distortion: f64,
+    pub octaves: u32,
+    pub mapping: Mapping,
+}
+
+impl Marble {
+    pub fn new(mapping: Mapping) -> Self {
+        Self {
+            ramp: ColorRamp::new(vec![
+                (0.0, DVec3::new(0.25, 0.25, 0.28)),
+                (0.5, DVec3::new(0.7, 0.7, 0.7)),
+                (1.0, DVec3::new(0.9, 0.9, 0.88)),
+            ]),
+            distortion: 5.0,
+            octaves: 6,
+            mapping,
+        }
+    }
+}
+
+impl TextureSource for Marble {
+    fn value(&self, ctx: &TextureContext) -> DVec3 {
+        let p = self.mapping.point(ctx);
+        let phase = p.x + self.distortion * turbulence(perlin, p, self.octaves);
+        self.ramp.eval(0.5 + 0.5 * phase.sin())
+    }
+}
+
+/// Growth rings around the z axis, one per unit of distance, slightly irregular.
+pub struct Wood {
+    pub ramp: ColorRamp,
+    // how much the rings deviate from circles, in rings
+    pub distortion: f64,
+    pub mapping: Mapping,
+}
+
+impl Wood {
+    pub fn new(mapping: Mapping) -> Self {
+        Self {
+            ramp: ColorRamp::new(vec![
+                (0.0, DVec3::new(0.55, 0.35, 0.17)),
+                (0.7, DVec3::new(0.45, 0.27, 0.12)),
+                (1.0, DVec3::new(0.3, 0.16, 0.06)),
+            ]),
+            distortion: 0.3,
+            mapping,
+        }
+    }
+}
+
+impl TextureSource for Wood {
+    fn value(&self, ctx: &TextureContext) -> DVec3 {
+        let p = self.mapping.point(ctx);
+        let radius = p.truncate().length() + self.distortion * fbm(perlin, p, 2);
+        self.ramp.eval(radius.rem_euclid(1.0))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GradientKind {
+    // along x, from 0 to 1
+    Linear,
+    // around the z axis, a full turn from -x
+    Radial,
+    // from 1 at the origin to 0 at distance 1
+    Spherical,
+}
+
+pub struct Gradient {
+    pub kind: GradientKind,
+    pub ramp: ColorRamp,
+    pub mapping: Mapping,
+}
+
+impl Gradient {
+    pub fn new(kind: GradientKind, ramp: ColorRamp, mapping: Mapping) -> Self {
+        Self {
+            kind,
+            ramp,
+            mapping,
+        }
+    }
+}
+
+impl TextureSource for Gradient {
+    fn value(&self, ctx: &TextureContext) -> DVec3 {
+        let p = self.mapping.point(ctx);
+        let t = match self.kind {
+            GradientKind::Linear => p.x,
+            GradientKind::Radial => p.y.atan2(p.x) / (2.0 * PI) + 0.5,
+            GradientKind::Spherical => 1.0 - p.length(),
+        };
+        self.ramp.eval(t.clamp(0.0, 1.0))
+    }
+}
+
+/// Running bond brickwork in the xy plane, every other row shifted by half a brick.
+pub struct Brick {
+    pub brick: DVec3,
+    pub mortar: DVec3,
+    pub size: DVec2,
+    pub mortar_width: f64,
+    // random darkening per brick, from 0 to 1
+    pub variation: f64,
+    pub mapping: Mapping,
+}
+
+impl Brick {
+    pub fn new(mapping: Mapping) -> Self {
+        Self {
+            brick: DVec3::new(0.5, 0.17, 0.1),
+            mortar: DVec3::new(0.6, 0.58, 0.55),
+            size: DVec2::new(0.5, 0.25),
+            mortar_width: 0.02,
+            variation: 0.3,
+            mapping,
+        }
+    }
+}
+
+impl TextureSource for Brick {
+    fn value(&self, ctx: &TextureContext) -> DVec3 {
+        let p = self.mapping.point(ctx);
+        let row = (p.y / self.size.y).floor();
+        let x = p.x / self.size.x + 0.5 * row.rem_euclid(2.0);
+        let column = x.floor();
+
+        // mortar along the lower and left edge of each brick
+        let in_brick = DVec2::new((x - column) * self.size.x, p.y - row * self.size.y);
+        if in_brick.min_element() < self.mortar_width {
+            return self.mortar;
+        }
+        let shade = unit(hash(column as i64, row as i64, 0));
+        self.brick * (1.0 - self.variation * shade)
+    }
+}
+
+/// Improved Perlin noise, roughly in [-1, 1] and zero at integer points.
+pub fn perlin(p: DVec3) -> f64 {
+    let cell = p.floor();
+    let f = p - cell;
+    let (x, y, z) = (cell.x as i64, cell.y as i64, cell.z as i64);
+    let fade = |t: f64| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+    let (u, v, w) = (fade(f.x), fade(f.y), fade(f.z));
+
+    let corner = |dx: i64, dy: i64, dz: i64| {
+        let offset = f - DVec3::new(dx as f64, dy as f64, dz as f64);
+        DVec3::dot(gradient(hash(x + dx, y + dy, z + dz)), offset)
+    };
+    let lerp = |a: f64, b: f64, t: f64| a + (b - a) * t;
+    lerp(
+        lerp(
+            lerp(corner(0, 0, 0), corner(1, 0, 0), u),
+            lerp(corner(0, 1, 0), corner(1, 1, 0), u),
+            v,
+        ),
+        lerp(
+            lerp(corner(0, 0, 1), corner(1, 0, 1), u),
+            lerp(corner(0, 1, 1), corner(1, 1, 1), u),
+            v,
+        ),
+        w,
+    )
+}
+
+/// Simplex noise, roughly in [-1, 1]. Cheaper than Perlin noise and without its
+/// axis-aligned artifacts.
+pub fn simplex(p: DVec3) -> f64 {
+    // skew to a grid of cubes that each split into six tetrahedra
+    const SKEW: f64 = 1.0 / 3.0;
+    const UNSKEW: f64 = 1.0 / 6.0;
+    let cell = (p + (p.x + p.y + p.z) * SKEW).floor();
+    let x0 = p - (cell - (cell.x + cell.y + cell.z) * UNSKEW);
+
+    // the tetrahedron is found by the order of the coordinates
+    let (i1, i2) = if x0.x >= x0.y {
+        if x0.y >= x0.z {
+            (DVec3::X, DVec3::new(1.0, 1.0, 0.0))
+        } else if x0.x >= x0.z {
+            (DVec3::X, DVec3::new(1.0, 0.0, 1.0))
+        } else {
+            (DVec3::Z, DVec3::new(1.0, 0.0, 1.0))
+        }
+    } else if x0.y < x0.z {
+        (DVec3::Z, DVec3::new(0.0, 1.0, 1.0))
+    } else if x0.x < x0.z {
+        (DVec3::Y, DVec3::new(0.0, 1.0, 1.0))
+    } else {
+        (DVec3::Y, DVec3::new(1.0, 1.0, 0.0))
+    };
+
+    [DVec3::ZERO, i1, i2, DVec3::ONE]
+        .iter()
+        .enumerate()
+        .map(|(i, &corner)| {
+            let offset = x0 - corner + i as f64 * UNSKEW;
+            let t = 0.6 - offset.length_squared();
+            if t <= 0.0 {
+                return 0.0;
+            }
+            let c = cell + corner;
+            let g = gradient(hash(c.x as i64, c.y as i64, c.z as i64));
+            t.powi(4) * DVec3::dot(g, offset)
+        })
+        .sum::<f64>()
+        * 32.0
+}
+
+/// Octaves of `noise` in [-1, 1], normalized to stay in that range.
+pub fn fbm(noise: fn(DVec3) -> f64, p: DVec3, octaves: u32) -> f64 {
+    let (mut sum, mut amplitude, mut total) = (0.0, 1.0, 0.0);
+    let mut frequency = 1.0;
+    for _ in 0..octaves.max(1) {
+        sum += amplitude * noise(p * frequency);
+        total += amplitude;
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    sum / total
+}
+
+/// Like `fbm` with the absolute value of each octave, in [0, 1].
+pub fn turbulence(noise: fn(DVec3) -> f64, p: DVec3, octaves: u32) -> f64 {
+    let (mut sum, mut amplitude, mut total) = (0.0, 1.0, 0.0);
+    let mut frequency = 1.0;
+    for _ in 0..octaves.max(1) {
+        sum += amplitude * noise(p * frequency).abs();
+        total += amplitude;
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    sum / total
+}
+
+/// Distances to the closest and second closest feature point, and a hash of the cell of
+/// the closest one.
+pub fn worley(p: DVec3) -> (f64, f64, u32) {
+    let cell = p.floor();
+    let (mut f1, mut f2, mut closest) = (f64::INFINITY, f64::INFINITY, 0);
+    for dz in -1..=1 {
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                let c = cell + DVec3::new(dx as f64, dy as f64, dz as f64);
+                let h = hash(c.x as i64, c.y as i64, c.z as i64);
+                let jitter = DVec3::new(unit(h), unit(mix(h ^ 1)), unit(mix(h ^ 2)));
+                let distance = (c + jitter).distance(p);
+                if distance < f1 {
+                    (f2, f1, closest) = (f1, distance, h);
+                } else if distance < f2 {
+                    f2 = distance;
+                }
+            }
+        }
+    }
+    (f1, f2, closest)
+}
+
+// one of the 12 directions to the edges of a cube, as in improved Perlin noise
+fn gradient(hash: u32) -> DVec3 {
+    match hash % 12 {
+        0 => DVec3::new(1.0, 1.0, 0.0),
+        1 => DVec3::new(-1.0, 1.0, 0.0),
+        2 => DVec3::new(1.0, -1.0, 0.0),
+        3 => DVec3::new(-1.0, -1.0, 0.0),
+        4 => DVec3::new(1.0, 0.0, 1.0),
+        5 => DVec3::new(-1.0, 0.0, 1.0),
+        6 => DVec3::new(1.0, 0.0, -1.0),
+        7 => DVec3::new(-1.0, 0.0, -1.0),
+        8 => DVec3::new(0.0, 1.0, 1.0),
+        9 => DVec3::new(0.0, -1.0, 1.0),
+        10 => DVec3::new(0.0, 1.0, -1.0),
+        _ => DVec3::new(0.0, -1.0, -1.0),
+    }
+}
+
+// deterministic per lattice point, so patterns don't change between renders
+fn hash(x: i64, y: i64, z: i64) -> u32 {
+    mix((x as u32).wrapping_mul(0x8da6_b343)
+        ^ (y as u32).wrapping_mul(0xd816_3841)
+        ^ (z as u32).wrapping_mul(0xcb1a_b31f))
+}
+
+fn mix(mut h: u32) -> u32 {
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7feb_352d);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846c_a68b);
+    h ^ (h >> 16)
+}
+
+// in [0, 1)
+fn unit(hash: u32) -> f64 {
+    hash as f64 / (u32::MAX as f64 + 1.0)
+}
@@ -1,48 +1,37 @@
-use crate::hittable::Hittable;
-
 use glam::DVec3;
 
+#[derive(Debug, Clone, Copy)]
 pub struct Ray {
     pub origin: DVec3,
     pub dir: DVec3,
+    // for texture filtering, on camera rays and their specular bounces
+    pub differential: Option<RayDifferential>,
+}
+
+/// Rays offset by a fraction of a pixel in x and y, see `DIFFERENTIAL_SPACING` in
+/// `camera`, whose spread estimates the footprint of a ray on the surfaces it hits.
+#[derive(Debug, Clone, Copy)]
+pub struct RayDifferential {
+    pub rx_origin: DVec3,
+    pub rx_dir: DVec3,
+    pub ry_origin: DVec3,
+    pub ry_dir: DVec3,
+}
+
+/// What a ray is traced for, which decides the objects it can see, see `Visibility`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RayKind {
+    Camera,
+    // scattered by a diffuse or glossy surface
+    Diffuse,
+    // reflected or refracted by a specular surface
+    Specular,
+    // towards a light, to test for occluders
+    Shadow,
 }
 
 impl Ray {
     pub fn at(&self, t: f64) -> DVec3 {
         self.origin + self.dir * t
     }
-
-    pub fn trace(&self, depth: u32, obj_list: &Vec<&dyn Hittable>, background: DVec3) -> DVec3 {
-        if depth == 0 {
-            return DVec3::ZERO;
-        }
-
-        let obj = obj_list.iter().fold(None, |acc, obj| {
-            match (acc, obj.hit(self)) {
-                // pick the closest hit
-                (None, None) => None,
-                (Some(x), None) => Some(x),
-                (None, Some(x)) => Some(x),
-                (Some(x), Some(y)) => {
-                    if x.t < y.t {
-                        Some(x)
-                    } else {
-                        Some(y)
-                    }
-                }
-            }
-        });
-        match obj {
-            Some(x) => {
-                let emission = x.material.emit();
-                let scatter = if let Some((scattered, attenuation)) = x.material.scatter(self, &x) {
-                    scattered.trace(depth - 1, obj_list, background) * attenuation
-                } else {
-                    DVec3::ZERO
-                };
-                emission + scatter
-            }
-            None => background,
-        }
-    }
 }
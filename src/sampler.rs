@@ -0,0 +1,178 @@
+use std::cell::RefCell;
+use std::f64::consts::PI;
+
+use fastrand::Rng;
+
+thread_local! {
+    static PRIMARY_SAMPLES: RefCell<Option<PrimarySamples>> = const { RefCell::new(None) };
+}
+
+/// Next random number in [0, 1) for the sample being traced. Everything that makes random
+/// decisions while tracing draws from here: the numbers come from the primary sample vector
+/// while a Metropolis chain is evaluating a sample, and from `fastrand`'s thread-local
+/// generator otherwise.
+pub fn f64() -> f64 {
+    PRIMARY_SAMPLES.with_borrow_mut(|primary| match primary {
+        Some(primary) => primary.next(),
+        None => fastrand::f64(),
+    })
+}
+
+pub fn bool() -> bool {
+    f64() < 0.5
+}
+
+/// Random index in 0..`n`.
+pub fn usize(n: usize) -> usize {
+    ((f64() * n as f64) as usize).min(n - 1)
+}
+
+/// Seeding the thread-local generator before each sample makes the sample depend only on
+/// the render seed, the pixel and the sample index. This keeps renders reproducible
+/// regardless of the order or the number of passes they are taken in.
+#[derive(Debug, Clone, Copy)]
+pub struct Sampler {
+    pub seed: u64,
+}
+
+impl Sampler {
+    pub fn new(seed: u64) -> Self {
+        Self { seed }
+    }
+
+    pub fn start_sample(&self, x: u32, y: u32, index: u32) {
+        let pixel = ((y as u64) << 32) | x as u64;
+        let hash = Self::mix(Self::mix(self.seed ^ Self::mix(pixel)) ^ index as u64);
+        fastrand::seed(hash);
+    }
+
+    /// Seeds the generator for the work shared by all samples with index `iteration`.
+    pub fn start_iteration(&self, iteration: u32) {
+        self.start_sample(u32::MAX, u32::MAX, iteration);
+    }
+
+    /// A random offset inside the pixel, in [-0.5, 0.5).
+    pub fn pixel_offset(&self) -> (f64, f64) {
+        (f64() - 0.5, f64() - 0.5)
+    }
+
+    // splitmix64 finalizer
+    pub fn mix(mut z: u64) -> u64 {
+        z = z.wrapping_add(0x9e3779b97f4a7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct PrimarySample {
+    value: f64,
+    // iteration of the last change, so mutations can be applied lazily
+    last_modified: u64,
+    backup: f64,
+    backup_modified: u64,
+}
+
+/// The random numbers of one Metropolis chain, after Kelemen et al. Each iteration either
+/// replaces all of them (large step) or perturbs them slightly (small step). Numbers are
+/// only created and mutated once a sample actually asks for them.
+pub struct PrimarySamples {
+    rng: Rng,
+    samples: Vec<PrimarySample>,
+    index: usize,
+    iteration: u64,
+    large_step: bool,
+    last_large_step: u64,
+    sigma: f64,
+    large_step_probability: f64,
+}
+
+impl PrimarySamples {
+    /// Two chains made with the same `seed` produce the same first sample.
+    pub fn new(seed: u64, sigma: f64, large_step_probability: f64) -> Self {
+        Self {
+            rng: Rng::with_seed(seed),
+            samples: Vec::new(),
+            index: 0,
+            iteration: 0,
+            large_step: true,
+            last_large_step: 0,
+            sigma,
+            large_step_probability,
+        }
+    }
+
+    pub fn start_iteration(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.rng.f64() < self.large_step_probability;
+    }
+
+    pub fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+
+    pub fn reject(&mut self) {
+        for sample in &mut self.samples {
+            if sample.last_modified == self.iteration {
+                sample.value = sample.backup;
+                sample.last_modified = sample.backup_modified;
+            }
+        }
+        self.iteration -= 1;
+    }
+
+    /// Random number for the chain itself, e.g. for accepting a mutation.
+    pub fn uniform(&mut self) -> f64 {
+        self.rng.f64()
+    }
+
+    /// Runs `f` with `sampler::f64` drawing from this sample vector.
+    pub fn evaluate<R>(&mut self, f: impl FnOnce() -> R) -> R {
+        self.index = 0;
+        let primary = std::mem::replace(self, Self::new(0, 0.0, 0.0));
+        PRIMARY_SAMPLES.with_borrow_mut(|current| *current = Some(primary));
+        let result = f();
+        *self = PRIMARY_SAMPLES
+            .with_borrow_mut(|current| current.take())
+            .unwrap();
+        result
+    }
+
+    fn next(&mut self) -> f64 {
+        let index = self.index;
+        self.index += 1;
+        if index >= self.samples.len() {
+            self.samples.resize(index + 1, PrimarySample::default());
+        }
+
+        let sample = &mut self.samples[index];
+        // a large step since the last use replaced the value without touching it
+        if sample.last_modified < self.last_large_step {
+            sample.value = self.rng.f64();
+            sample.last_modified = self.last_large_step;
+        }
+
+        sample.backup = sample.value;
+        sample.backup_modified = sample.last_modified;
+        if self.large_step {
+            sample.value = self.rng.f64();
+        } else {
+            // catch up on the small steps skipped since the last use
+            let steps = (self.iteration - sample.last_modified) as f64;
+            sample.value += Self::normal(&mut self.rng) * self.sigma * steps.sqrt();
+            sample.value -= sample.value.floor();
+        }
+        sample.last_modified = self.iteration;
+        sample.value
+    }
+
+    // Box-Muller
+    fn normal(rng: &mut Rng) -> f64 {
+        let u1 = 1.0 - rng.f64();
+        let u2 = rng.f64();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+    }
+}
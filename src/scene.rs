@@ -1,26 +1,42 @@
+#![allow(dead_code)]
+
 use crate::camera::Camera;
 use crate::hittable::{Hittable, Triangle};
+use crate::light::{DirectionalLight, LightSource, PointLight, Portal, SpotLight};
 use crate::material::Lambertian;
+use crate::sky::PhysicalSky;
+use crate::texture::{ColorSpace, Filter, Texture, WrapMode};
+
+use std::path::Path;
+
+use gltf::khr_lights_punctual::{Kind, Light};
 
 use glam::{DMat4, DVec3, Mat4, Vec3};
 use gltf::Buffer;
+use gltf::image::{Image, Source};
 use gltf::mesh::Reader;
-use gltf::{Node, buffer::Data};
+use gltf::texture::{MinFilter, WrappingMode};
+use gltf::{Document, Node, buffer::Data};
 
 pub struct Scene {
     pub hittables: Vec<Box<dyn Hittable>>,
+    pub lights: Vec<Box<dyn LightSource>>,
+    // for the environment light, from meshes named "Portal..."
+    pub portals: Vec<Portal>,
     pub camera: Camera,
 }
 
 impl Scene {
     pub fn import(file_path: &str) -> Vec<Self> {
-        let (document, buffers, _) = gltf::import(file_path).unwrap();
+        let (document, buffers) = Self::open(file_path);
 
         document
             .scenes()
             .map(|scene| {
                 let mut result = Scene {
                     hittables: Vec::new(),
+                    lights: Vec::new(),
+                    portals: Vec::new(),
                     camera: Camera::default(),
                 };
 
@@ -32,10 +48,125 @@ impl Scene {
             .collect()
     }
 
+    /// Loads every texture of a glTF file, in the order of its textures array, with the wrap
+    /// modes and filtering of its sampler. Images used as base color or emission are taken
+    /// as sRGB, the rest as linear data. Textures whose image can't be loaded are skipped
+    /// with a warning and left as `None`.
+    pub fn import_textures(file_path: &str) -> Vec<Option<Texture>> {
+        let (document, buffers) = Self::open(file_path);
+        let base = Path::new(file_path).parent().unwrap_or(Path::new(""));
+
+        let srgb: Vec<usize> = document
+            .materials()
+            .flat_map(|material| {
+                [
+                    material.pbr_metallic_roughness().base_color_texture(),
+                    material.emissive_texture(),
+                ]
+            })
+            .flatten()
+            .map(|info| info.texture().source().index())
+            .collect();
+
+        let images: Vec<Option<Texture>> = document
+            .images()
+            .map(|image| {
+                let color_space = if srgb.contains(&image.index()) {
+                    ColorSpace::Srgb
+                } else {
+                    ColorSpace::Linear
+                };
+                Self::load_image(&image, &buffers, base, color_space)
+                    .inspect_err(|err| eprintln!("Skipping texture image {}: {err}", image.index()))
+                    .ok()
+            })
+            .collect();
+
+        document
+            .textures()
+            .map(|texture| {
+                let mut result = images[texture.source().index()].clone()?;
+                let sampler = texture.sampler();
+                result.wrap_u = Self::get_wrap_mode(sampler.wrap_s());
+                result.wrap_v = Self::get_wrap_mode(sampler.wrap_t());
+                result.filter = match sampler.min_filter() {
+                    Some(MinFilter::Nearest) => Filter::Nearest,
+                    Some(MinFilter::Linear) => Filter::Bilinear,
+                    // mipmapped or left to the renderer
+                    _ => Filter::Trilinear,
+                };
+                Some(result)
+            })
+            .collect()
+    }
+
+    fn load_image(
+        image: &Image,
+        buffers: &[Data],
+        base: &Path,
+        color_space: ColorSpace,
+    ) -> Result<Texture, String> {
+        match image.source() {
+            Source::View { view, .. } => {
+                let buffer = &buffers[view.buffer().index()];
+                let bytes = &buffer[view.offset()..view.offset() + view.length()];
+                Texture::from_memory(bytes, color_space).map_err(|err| err.to_string())
+            }
+            Source::Uri { uri, .. } => match uri.strip_prefix("data:") {
+                // data:[<media type>];base64,<data>
+                Some(data) => {
+                    let (_, encoded) = data
+                        .split_once(";base64,")
+                        .ok_or("data URI is not base64 encoded")?;
+                    let bytes = base64::decode(encoded).map_err(|err| err.to_string())?;
+                    Texture::from_memory(&bytes, color_space).map_err(|err| err.to_string())
+                }
+                None => {
+                    let path = urlencoding::decode(uri).map_err(|err| err.to_string())?;
+                    Texture::load(base.join(path.as_ref()), color_space)
+                        .map_err(|err| err.to_string())
+                }
+            },
+        }
+    }
+
+    fn get_wrap_mode(mode: WrappingMode) -> WrapMode {
+        match mode {
+            WrappingMode::Repeat => WrapMode::Repeat,
+            WrappingMode::MirroredRepeat => WrapMode::MirroredRepeat,
+            WrappingMode::ClampToEdge => WrapMode::ClampToEdge,
+        }
+    }
+
+    // the document and its buffers, without decoding any images
+    fn open(file_path: &str) -> (Document, Vec<Data>) {
+        let gltf = gltf::Gltf::open(file_path).unwrap();
+        let base = Path::new(file_path).parent();
+        let buffers = gltf::import_buffers(&gltf.document, base, gltf.blob).unwrap();
+        (gltf.document, buffers)
+    }
+
+    /// Lights the scene with `sky` and its sun instead of the camera's constant background.
+    /// The sky is baked into an environment light of `width` by `height`, which looks
+    /// through the scene's portals.
+    pub fn set_sky(&mut self, sky: &PhysicalSky, width: u32, height: u32) {
+        let mut environment = sky.environment(width, height);
+        for portal in &self.portals {
+            environment.add_portal(*portal);
+        }
+        self.lights.push(Box::new(environment));
+        self.lights.push(Box::new(sky.sun()));
+        self.camera.background = DVec3::ZERO;
+    }
+
     pub fn ref_vec(&self) -> Vec<&dyn Hittable> {
         self.hittables.iter().map(|h| h.as_ref()).collect()
     }
 
+    pub fn light_ref_vec(&self) -> Vec<&dyn LightSource> {
+        self.lights.iter().map(|l| l.as_ref()).collect()
+    }
+
     fn process_node(&mut self, node: &Node, parent_transform: DMat4, buffers: &[Data]) {
         let local_transform = Mat4::from_cols_array_2d(&node.transform().matrix()).as_dmat4();
         let transform = parent_transform * local_transform;
@@ -45,10 +176,21 @@ impl Scene {
             self.camera = camera;
         }
 
+        if let Some(light) = node.light() {
+            self.lights.push(Self::get_light(&light, transform));
+        }
+
         if let Some(mesh) = node.mesh() {
+            let is_portal = node
+                .name()
+                .is_some_and(|name| name.to_lowercase().starts_with("portal"));
             for primitive in mesh.primitives() {
                 let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
-                self.build_triangles(&reader, transform);
+                if is_portal {
+                    self.portals.extend(Self::get_portal(&reader, transform));
+                } else {
+                    self.build_triangles(&reader, transform);
+                }
             }
         }
     }
@@ -77,11 +219,104 @@ impl Scene {
         })
     }
 
-    fn build_triangles<'a, 's, F>(&mut self, reader: &Reader<'a, 's, F>, transform: DMat4)
+    fn get_light(light: &Light, transform: DMat4) -> Box<dyn LightSource> {
+        // glTF uses photometric units, candela for point and spot lights and lux for
+        // directional lights, at 683 lm/W
+        let color = Vec3::from_array(light.color()).as_dvec3();
+        let power = color * light.intensity() as f64 / 683.0;
+        let range = light.range().map(|range| range as f64);
+
+        // lights point along their local -z axis
+        let pos = transform.transform_point3(DVec3::ZERO);
+        let dir = transform.transform_vector3(-DVec3::Z).normalize();
+
+        // glTF has no soft shadows, their size comes from the light's custom properties:
+        // `radius` for point and spot lights, `angular_diameter` in degrees for directional
+        // ones
+        let extras: Option<serde_json::Value> = light
+            .extras()
+            .as_ref()
+            .and_then(|extras| serde_json::from_str(extras.get()).ok());
+        let extra = |key: &str| {
+            extras
+                .as_ref()
+                .and_then(|extras| extras.get(key)?.as_f64())
+                .unwrap_or(0.0)
+        };
+        let radius = extra("radius");
+
+        match light.kind() {
+            Kind::Directional => {
+                Box::new(DirectionalLight::new(dir, power, extra("angular_diameter")))
+            }
+            Kind::Point => {
+                let mut point = PointLight::new(pos, power, radius);
+                point.range = range;
+                Box::new(point)
+            }
+            Kind::Spot {
+                inner_cone_angle,
+                outer_cone_angle,
+            } => {
+                let mut spot = SpotLight::new(
+                    pos,
+                    dir,
+                    power,
+                    radius,
+                    (inner_cone_angle as f64).to_degrees(),
+                    (outer_cone_angle as f64).to_degrees(),
+                );
+                spot.range = range;
+                Box::new(spot)
+            }
+        }
+    }
+
+    // A quad whose front faces the interior, like an area light shining in through it.
+    fn get_portal<'a, 's, F>(reader: &Reader<'a, 's, F>, transform: DMat4) -> Option<Portal>
     where
         F: Clone + Fn(Buffer<'a>) -> Option<&'s [u8]>,
     {
-        let positions = match reader.read_positions() {
+        let positions = Self::read_positions(reader, transform);
+        let indices: Vec<u32> = reader.read_indices()?.into_u32().collect();
+        let [a, b, c] = [0, 1, 2].map(|i| positions[indices[i] as usize]);
+        let outwards = -DVec3::cross(b - a, c - a);
+
+        // the two corners closest to the first one span the rectangle. Vertices split for
+        // different normals or uvs may not be bit-identical after the transform, so closer
+        // than a small fraction of the size counts as the same point
+        let corner = positions[0];
+        let size_squared = positions
+            .iter()
+            .map(|p| p.distance_squared(corner))
+            .fold(0.0, f64::max);
+        let epsilon = size_squared * 1e-8;
+        let mut others: Vec<DVec3> = positions
+            .iter()
+            .copied()
+            .filter(|p| p.distance_squared(corner) > epsilon)
+            .collect();
+        others.sort_by(|p, q| {
+            p.distance_squared(corner)
+                .total_cmp(&q.distance_squared(corner))
+        });
+        others.dedup_by(|p, q| p.distance_squared(*q) <= epsilon);
+        let (mut edge1, mut edge2) = (*others.first()? - corner, *others.get(1)? - corner);
+        if DVec3::dot(DVec3::cross(edge1, edge2), outwards) < 0.0 {
+            std::mem::swap(&mut edge1, &mut edge2);
+        }
+        Some(Portal {
+            corner,
+            edge1,
+            edge2,
+        })
+    }
+
+    fn read_positions<'a, 's, F>(reader: &Reader<'a, 's, F>, transform: DMat4) -> Vec<DVec3>
+    where
+        F: Clone + Fn(Buffer<'a>) -> Option<&'s [u8]>,
+    {
+        match reader.read_positions() {
             Some(positions) => positions
                 .map(|p| {
                     let pos_vec4 = transform * Vec3::from_array(p).extend(1.0).as_dvec4();
@@ -89,7 +324,14 @@ impl Scene {
                 })
                 .collect::<Vec<_>>(),
             None => todo!(),
-        };
+        }
+    }
+
+    fn build_triangles<'a, 's, F>(&mut self, reader: &Reader<'a, 's, F>, transform: DMat4)
+    where
+        F: Clone + Fn(Buffer<'a>) -> Option<&'s [u8]>,
+    {
+        let positions = Self::read_positions(reader, transform);
 
         // try using indices
         if let Some(indices) = reader.read_indices() {
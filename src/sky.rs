@@ -0,0 +1,246 @@
+#![allow(dead_code)]
+
+use std::f64::consts::PI;
+
+use crate::light::{DirectionalLight, EnvironmentLight};
+
+use glam::DVec3;
+
+/// Clear sky after Preetham et al., "A Practical Analytic Model for Daylight". The sun is
+/// `sun_elevation` degrees above the horizon and `sun_azimuth` degrees clockwise from north,
+/// which is -z, with east at +x. `turbidity` ranges from about 2 for a clear sky to 10 for
+/// a hazy one. Below the horizon is a diffuse ground of `ground_albedo` lit by the sky and
+/// the sun.
+///
+/// Radiance follows the photometric values of the model at 683 lm/W, the same as imported
+/// glTF lights.
+///
+/// The sky replaces the constant background of the camera, see `Scene::set_sky`.
+pub struct PhysicalSky {
+    pub sun_elevation: f64,
+    pub sun_azimuth: f64,
+    pub turbidity: f64,
+    pub ground_albedo: DVec3,
+    sun_dir: DVec3,
+    // zenith value and Perez coefficients of Y, x and y
+    zenith: [f64; 3],
+    perez: [[f64; 5]; 3],
+    ground: DVec3,
+}
+
+impl PhysicalSky {
+    pub fn new(sun_elevation: f64, sun_azimuth: f64, turbidity: f64, ground_albedo: DVec3) -> Self {
+        let t = turbidity;
+        let elevation = sun_elevation.to_radians();
+        let azimuth = sun_azimuth.to_radians();
+        let sun_dir = DVec3::new(
+            elevation.cos() * azimuth.sin(),
+            elevation.sin(),
+            -elevation.cos() * azimuth.cos(),
+        );
+
+        // the model isn't defined for the sun below the horizon
+        let theta_s = (PI / 2.0 - elevation).clamp(0.0, PI / 2.0);
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let theta = [theta_s.powi(3), theta_s.powi(2), theta_s, 1.0];
+        let chromaticity = |m: [[f64; 4]; 3]| {
+            let row = |r: [f64; 4]| r.iter().zip(theta).map(|(a, b)| a * b).sum::<f64>();
+            t * t * row(m[0]) + t * row(m[1]) + row(m[2])
+        };
+        let zenith_x = chromaticity([
+            [0.00166, -0.00375, 0.00209, 0.0],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886],
+        ]);
+        let zenith_y = chromaticity([
+            [0.00275, -0.00610, 0.00317, 0.0],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688],
+        ]);
+
+        let perez = [
+            [
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ],
+            [
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ],
+            [
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ],
+        ];
+        // normalized so the formula gives the zenith value straight up
+        let zenith = [zenith_luminance, zenith_x, zenith_y];
+        let zenith = std::array::from_fn(|i| zenith[i] / perez_f(perez[i], 0.0, theta_s));
+
+        let mut sky = Self {
+            sun_elevation,
+            sun_azimuth,
+            turbidity,
+            ground_albedo,
+            sun_dir,
+            zenith,
+            perez,
+            ground: DVec3::ZERO,
+        };
+        sky.ground = ground_albedo * sky.horizontal_irradiance() / PI;
+        sky
+    }
+
+    /// Normalized direction towards the sun.
+    pub fn sun_dir(&self) -> DVec3 {
+        self.sun_dir
+    }
+
+    pub fn radiance(&self, dir: DVec3) -> DVec3 {
+        let dir = dir.normalize();
+        if dir.y < 0.0 {
+            return self.ground;
+        }
+        if self.sun_elevation < 0.0 {
+            return DVec3::ZERO;
+        }
+
+        let theta = dir.y.clamp(0.0, 1.0).acos();
+        let gamma = DVec3::dot(dir, self.sun_dir).clamp(-1.0, 1.0).acos();
+        let [lum, x, y] =
+            std::array::from_fn(|i| self.zenith[i] * perez_f(self.perez[i], theta, gamma));
+        // kcd/m² to W/(sr m²)
+        xyy_to_rgb(x, y, lum * 1000.0 / 683.0)
+    }
+
+    /// Irradiance from the sun on a surface facing it. The extraterrestrial sunlight is
+    /// attenuated by Rayleigh and aerosol scattering along the way through the atmosphere.
+    pub fn sun_irradiance(&self) -> DVec3 {
+        if self.sun_elevation <= 0.0 {
+            return DVec3::ZERO;
+        }
+        // relative optical air mass, after Kasten and Young
+        let zenith_angle = 90.0 - self.sun_elevation;
+        let air_mass = 1.0
+            / (zenith_angle.to_radians().cos() + 0.50572 * (96.07995 - zenith_angle).powf(-1.6364));
+        // Ångström's turbidity coefficient from the Linke-like turbidity
+        let beta = 0.04608 * self.turbidity - 0.04586;
+        // red, green and blue wavelengths in micrometers
+        let transmittance = [0.65, 0.55, 0.45].map(|lambda: f64| {
+            let rayleigh = 0.008735 * lambda.powf(-4.08);
+            let aerosol = beta * lambda.powf(-1.3);
+            (-air_mass * (rayleigh + aerosol)).exp()
+        });
+        // about 128 klx outside the atmosphere
+        DVec3::from_array(transmittance) * 128_000.0 / 683.0
+    }
+
+    /// The sky baked into an importance sampled environment light of `width` by `height`.
+    pub fn environment(&self, width: u32, height: u32) -> EnvironmentLight {
+        EnvironmentLight::from_fn(width, height, |dir| self.radiance(dir))
+    }
+
+    /// The sun as a light source, the size of the real one.
+    pub fn sun(&self) -> DirectionalLight {
+        DirectionalLight::new(-self.sun_dir, self.sun_irradiance(), 0.53)
+    }
+
+    // irradiance on the ground from the sun and the upper hemisphere of the sky
+    fn horizontal_irradiance(&self) -> DVec3 {
+        const STEPS: u32 = 64;
+        let mut irradiance = self.sun_irradiance() * self.sun_dir.y.max(0.0);
+        for i in 0..STEPS {
+            let theta = (i as f64 + 0.5) / STEPS as f64 * PI / 2.0;
+            for j in 0..STEPS * 4 {
+                let phi = (j as f64 + 0.5) / (STEPS * 4) as f64 * 2.0 * PI;
+                let dir = DVec3::new(
+                    theta.sin() * phi.cos(),
+                    theta.cos(),
+                    theta.sin() * phi.sin(),
+                );
+                let solid_angle =
+                    theta.sin() * (PI / 2.0 / STEPS as f64) * (2.0 * PI / (STEPS * 4) as f64);
+                irradiance += self.radiance(dir) * theta.cos() * solid_angle;
+            }
+        }
+        irradiance
+    }
+}
+
+// Perez et al. luminance distribution, for a direction `theta` from the zenith and
+// `gamma` from the sun
+fn perez_f([a, b, c, d, e]: [f64; 5], theta: f64, gamma: f64) -> f64 {
+    let cos_theta = theta.cos().max(0.01);
+    (1.0 + a * (b / cos_theta).exp()) * (1.0 + c * (d * gamma).exp() + e * gamma.cos().powi(2))
+}
+
+// CIE xyY to linear sRGB
+fn xyy_to_rgb(x: f64, y: f64, lum: f64) -> DVec3 {
+    if y <= 0.0 {
+        return DVec3::ZERO;
+    }
+    let (cx, cy, cz) = (x / y * lum, lum, (1.0 - x - y) / y * lum);
+    DVec3::new(
+        3.2404542 * cx - 1.5371385 * cy - 0.4985314 * cz,
+        -0.9692660 * cx + 1.8760108 * cy + 0.0415560 * cz,
+        0.0556434 * cx - 0.2040259 * cy + 1.0572252 * cz,
+    )
+    .max(DVec3::ZERO)
+}
+
+/// Elevation and azimuth (clockwise from north) of the sun in degrees, seen from
+/// `latitude` and `longitude` degrees (north and east positive) at `utc_hours` on the
+/// given day. Follows NOAA's general solar position calculation, good to a fraction of a
+/// degree.
+pub fn sun_position(
+    year: i32,
+    month: u32,
+    day: u32,
+    utc_hours: f64,
+    latitude: f64,
+    longitude: f64,
+) -> (f64, f64) {
+    let leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+    let days_before = [0, 31, 59, 90, 120, 151, 181, 212, 243, 273, 304, 334];
+    let day_of_year =
+        days_before[(month as usize - 1).min(11)] + day + u32::from(leap && month > 2);
+    let days_in_year = if leap { 366.0 } else { 365.0 };
+
+    // fractional year
+    let g = 2.0 * PI / days_in_year * (day_of_year as f64 - 1.0 + (utc_hours - 12.0) / 24.0);
+    // in minutes
+    let equation_of_time = 229.18
+        * (0.000075 + 0.001868 * g.cos()
+            - 0.032077 * g.sin()
+            - 0.014615 * (2.0 * g).cos()
+            - 0.040849 * (2.0 * g).sin());
+    let declination = 0.006918 - 0.399912 * g.cos() + 0.070257 * g.sin()
+        - 0.006758 * (2.0 * g).cos()
+        + 0.000907 * (2.0 * g).sin()
+        - 0.002697 * (3.0 * g).cos()
+        + 0.00148 * (3.0 * g).sin();
+
+    let true_solar_minutes = utc_hours * 60.0 + equation_of_time + 4.0 * longitude;
+    let hour_angle = (true_solar_minutes / 4.0 - 180.0).to_radians();
+    let lat = latitude.to_radians();
+
+    let cos_zenith = (lat.sin() * declination.sin()
+        + lat.cos() * declination.cos() * hour_angle.cos())
+    .clamp(-1.0, 1.0);
+    let elevation = 90.0 - cos_zenith.acos().to_degrees();
+    let azimuth = hour_angle
+        .sin()
+        .atan2(hour_angle.cos() * lat.sin() - declination.tan() * lat.cos())
+        .to_degrees()
+        + 180.0;
+    (elevation, azimuth.rem_euclid(360.0))
+}
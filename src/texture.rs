@@ -1,20 +1,162 @@
 #![allow(dead_code)]
 
-use glam::DVec3;
+use std::path::Path;
 
+use glam::{DVec2, DVec3};
+use image::{DynamicImage, ImageBuffer, ImageFormat, Rgba};
+
+/// File formats `Texture::save` writes, all with alpha.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    // gamma encoded with straight alpha, 8 or 16 bits per channel
+    Png8,
+    Png16,
+    // linear 32 bit floats with premultiplied alpha, keeping values above one
+    Exr,
+}
+
+/// How the values of 8 and 16 bit images are encoded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorSpace {
+    // colors, like albedo or emission
+    Srgb,
+    // data, like normals, roughness or masks
+    Linear,
+}
+
+/// What lookups outside of [0, 1] read, like glTF samplers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WrapMode {
+    Repeat,
+    MirroredRepeat,
+    ClampToEdge,
+}
+
+/// How `sample` and `sample_footprint` filter texels. Trilinear and EWA read the mipmaps,
+/// see `generate_mipmaps`, and behave like bilinear without a footprint.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    Nearest,
+    Bilinear,
+    Trilinear,
+    // elliptical weighted average, sharper than trilinear at grazing angles
+    Ewa,
+}
+
+/// Where a texture is looked up, see `HitRecord::texture_context`.
+#[derive(Debug, Clone, Copy)]
+pub struct TextureContext {
+    pub pos: DVec3,
+    pub uv: DVec2,
+    // footprint towards the neighbouring pixels, zero without ray differentials
+    pub duv_dx: DVec2,
+    pub duv_dy: DVec2,
+}
+
+/// A color or scalar parameter that varies over surfaces: an image, a procedural pattern
+/// from `procedural`, or a constant `DVec3` or `f64`.
+pub trait TextureSource {
+    fn value(&self, ctx: &TextureContext) -> DVec3;
+
+    /// For scalar parameters like roughness, the mean of the channels.
+    fn scalar(&self, ctx: &TextureContext) -> f64 {
+        let value = self.value(ctx);
+        (value.x + value.y + value.z) / 3.0
+    }
+
+    /// Mean over the unit square of texture coordinates, e.g. for the power of a textured
+    /// emitter. Estimated on a grid unless overridden.
+    fn average(&self) -> DVec3 {
+        const STEPS: u32 = 16;
+        let mut sum = DVec3::ZERO;
+        for y in 0..STEPS {
+            for x in 0..STEPS {
+                let uv = (DVec2::new(x as f64, y as f64) + 0.5) / STEPS as f64;
+                sum += self.value(&TextureContext {
+                    pos: uv.extend(0.0),
+                    uv,
+                    duv_dx: DVec2::ZERO,
+                    duv_dy: DVec2::ZERO,
+                });
+            }
+        }
+        sum / (STEPS * STEPS) as f64
+    }
+}
+
+impl TextureSource for Texture {
+    fn value(&self, ctx: &TextureContext) -> DVec3 {
+        self.sample_footprint(ctx.uv, ctx.duv_dx, ctx.duv_dy)
+    }
+
+    fn average(&self) -> DVec3 {
+        self.buffer.iter().sum::<DVec3>() / self.buffer.len() as f64
+    }
+}
+
+impl TextureSource for DVec3 {
+    fn value(&self, _ctx: &TextureContext) -> DVec3 {
+        *self
+    }
+
+    fn average(&self) -> DVec3 {
+        *self
+    }
+}
+
+impl TextureSource for f64 {
+    fn value(&self, _ctx: &TextureContext) -> DVec3 {
+        DVec3::splat(*self)
+    }
+
+    fn scalar(&self, _ctx: &TextureContext) -> f64 {
+        *self
+    }
+
+    fn average(&self) -> DVec3 {
+        DVec3::splat(*self)
+    }
+}
+
+impl<T: TextureSource + ?Sized> TextureSource for &T {
+    fn value(&self, ctx: &TextureContext) -> DVec3 {
+        (**self).value(ctx)
+    }
+
+    fn scalar(&self, ctx: &TextureContext) -> f64 {
+        (**self).scalar(ctx)
+    }
+
+    fn average(&self) -> DVec3 {
+        (**self).average()
+    }
+}
+
+// longest axis of an EWA footprint relative to its shortest, longer ones are blurred
+const MAX_ANISOTROPY: f64 = 8.0;
+
+#[derive(Clone)]
 pub struct Texture {
     pub width: u32,
     pub height: u32,
     buffer: Vec<DVec3>,
+    // coverage, the colors are premultiplied by it
+    alpha: Vec<f64>,
+    pub wrap_u: WrapMode,
+    pub wrap_v: WrapMode,
+    pub filter: Filter,
+    // halving in size down to a single texel, empty until generated
+    mipmaps: Vec<Texture>,
 }
 
 impl Texture {
     pub fn new(width: u32, height: u32) -> Self {
-        Self {
+        Self::from_buffers(
             width,
             height,
-            buffer: vec![DVec3::ZERO; (width * height) as usize],
-        }
+            vec![DVec3::ZERO; (width * height) as usize],
+            vec![1.0; (width * height) as usize],
+        )
     }
 
     pub fn from_rgb_buffer(width: u32, height: u32, buffer: &[u8]) -> Self {
@@ -26,23 +168,266 @@ impl Texture {
                 i[2] as f64 / 255.0,
             )))
         }
+        Self::from_buffers(width, height, buf, vec![1.0; (width * height) as usize])
+    }
+
+    /// Loads an 8 or 16 bit, float or grayscale image, with alpha if it has one. Float
+    /// formats like .hdr and .exr are always taken as linear. The texture is filtered
+    /// trilinearly, with its mipmaps generated.
+    pub fn load(path: impl AsRef<Path>, color_space: ColorSpace) -> image::ImageResult<Self> {
+        Ok(Self::from_image(image::open(path)?, color_space))
+    }
+
+    /// Like `load`, for an encoded image in memory, e.g. embedded in a glTF file.
+    pub fn from_memory(bytes: &[u8], color_space: ColorSpace) -> image::ImageResult<Self> {
+        Ok(Self::from_image(
+            image::load_from_memory(bytes)?,
+            color_space,
+        ))
+    }
+
+    pub fn from_image(image: DynamicImage, color_space: ColorSpace) -> Self {
+        // float formats are linear and already premultiplied, like the .exr we write
+        let float = matches!(
+            image,
+            DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)
+        );
+        let linear = float || color_space == ColorSpace::Linear;
+        // grayscale is expanded to rgb, alpha defaults to opaque
+        let image = image.into_rgba32f();
+        let (buffer, alpha) = image
+            .pixels()
+            .map(|p| {
+                let color = DVec3::new(p[0] as f64, p[1] as f64, p[2] as f64);
+                let color = if linear {
+                    color
+                } else {
+                    Self::to_linear(color)
+                };
+                let alpha = p[3] as f64;
+                if float {
+                    (color, alpha)
+                } else {
+                    (color * alpha, alpha)
+                }
+            })
+            .unzip();
+        let mut texture = Self::from_buffers(image.width(), image.height(), buffer, alpha);
+        texture.filter = Filter::Trilinear;
+        texture.generate_mipmaps();
+        texture
+    }
+
+    fn from_buffers(width: u32, height: u32, buffer: Vec<DVec3>, alpha: Vec<f64>) -> Self {
         Self {
             width,
             height,
-            buffer: buf,
+            buffer,
+            alpha,
+            wrap_u: WrapMode::Repeat,
+            wrap_v: WrapMode::Repeat,
+            filter: Filter::Bilinear,
+            mipmaps: Vec::new(),
         }
     }
 
+    pub fn get(&self, x: u32, y: u32) -> DVec3 {
+        self.buffer[(y * self.width + x) as usize]
+    }
+
     pub fn set(&mut self, x: u32, y: u32, color: DVec3) {
         self.buffer[(y * self.width + x) as usize] = color;
     }
 
+    pub fn get_alpha(&self, x: u32, y: u32) -> f64 {
+        self.alpha[(y * self.width + x) as usize]
+    }
+
+    pub fn set_alpha(&mut self, x: u32, y: u32, alpha: f64) {
+        self.alpha[(y * self.width + x) as usize] = alpha;
+    }
+
+    /// Box filters the texture down to a single texel, for trilinear and EWA filtering.
+    pub fn generate_mipmaps(&mut self) {
+        self.mipmaps.clear();
+        let (mut width, mut height) = (self.width, self.height);
+        while width > 1 || height > 1 {
+            let finer = self.mipmaps.last().unwrap_or(self);
+            let (fine_width, fine_height) = (width, height);
+            width = (width / 2).max(1);
+            height = (height / 2).max(1);
+            let mut level = Self::new(width, height);
+            for y in 0..height {
+                for x in 0..width {
+                    // odd sizes fold their last row or column into the previous texel
+                    let xs = 2 * x..(2 * x + 2 + (x == width - 1) as u32).min(fine_width);
+                    let ys = 2 * y..(2 * y + 2 + (y == height - 1) as u32).min(fine_height);
+                    let count = (xs.len() * ys.len()) as f64;
+                    let (mut color, mut alpha) = (DVec3::ZERO, 0.0);
+                    for fy in ys {
+                        for fx in xs.clone() {
+                            color += finer.get(fx, fy);
+                            alpha += finer.get_alpha(fx, fy);
+                        }
+                    }
+                    level.set(x, y, color / count);
+                    level.set_alpha(x, y, alpha / count);
+                }
+            }
+            self.mipmaps.push(level);
+        }
+    }
+
     pub fn sample(&self, u: f64, v: f64) -> DVec3 {
-        let u = u.clamp(0.0, 1.0);
-        let v = 1.0 - v.clamp(0.0, 1.0); // flip v to image space
-        let x = (u * self.width as f64) as u32;
-        let y = (v * self.height as f64) as u32;
-        self.buffer[(y * self.width + x) as usize]
+        self.sample_footprint(DVec2::new(u, v), DVec2::ZERO, DVec2::ZERO)
+    }
+
+    /// Filtered lookup over the parallelogram spanned by how `uv` changes towards the
+    /// neighbouring pixels, e.g. from ray differentials.
+    pub fn sample_footprint(&self, uv: DVec2, duv_dx: DVec2, duv_dy: DVec2) -> DVec3 {
+        // flip v to image space
+        let st = DVec2::new(uv.x, 1.0 - uv.y);
+        let (d0, d1) = (
+            duv_dx * DVec2::new(1.0, -1.0),
+            duv_dy * DVec2::new(1.0, -1.0),
+        );
+        match self.filter {
+            Filter::Nearest => self.nearest(st),
+            Filter::Bilinear => self.bilinear(st),
+            Filter::Trilinear => {
+                let width = d0.abs().max(d1.abs()).max_element() * 2.0;
+                self.trilinear(st, width)
+            }
+            Filter::Ewa => self.ewa(st, d0, d1),
+        }
+    }
+
+    fn nearest(&self, st: DVec2) -> DVec3 {
+        let x = wrap(
+            self.wrap_u,
+            (st.x * self.width as f64).floor() as i64,
+            self.width,
+        );
+        let y = wrap(
+            self.wrap_v,
+            (st.y * self.height as f64).floor() as i64,
+            self.height,
+        );
+        self.get(x, y)
+    }
+
+    // between the centers of the four closest texels
+    fn bilinear(&self, st: DVec2) -> DVec3 {
+        let x = st.x * self.width as f64 - 0.5;
+        let y = st.y * self.height as f64 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+        let texel = |dx: i64, dy: i64| {
+            self.get(
+                wrap(self.wrap_u, x0 as i64 + dx, self.width),
+                wrap(self.wrap_v, y0 as i64 + dy, self.height),
+            )
+        };
+        (texel(0, 0) * (1.0 - tx) + texel(1, 0) * tx) * (1.0 - ty)
+            + (texel(0, 1) * (1.0 - tx) + texel(1, 1) * tx) * ty
+    }
+
+    // 0 for the full resolution texture, or the mipmap at `level - 1`
+    fn level(&self, level: usize) -> &Texture {
+        match level {
+            0 => self,
+            _ => &self.mipmaps[level - 1],
+        }
+    }
+
+    // mipmap level whose texels are `width` wide in texture coordinates, fractional
+    // between two levels
+    fn level_for(&self, width: f64) -> f64 {
+        let size = self.width.max(self.height) as f64;
+        (width * size)
+            .max(f64::MIN_POSITIVE)
+            .log2()
+            .clamp(0.0, self.mipmaps.len() as f64)
+    }
+
+    fn trilinear(&self, st: DVec2, width: f64) -> DVec3 {
+        let level = self.level_for(width);
+        let (fine, t) = (level.floor() as usize, level.fract());
+        if t == 0.0 {
+            return self.level(fine).bilinear(st);
+        }
+        self.level(fine).bilinear(st) * (1.0 - t) + self.level(fine + 1).bilinear(st) * t
+    }
+
+    fn ewa(&self, st: DVec2, d0: DVec2, d1: DVec2) -> DVec3 {
+        let (major, mut minor) = if d0.length_squared() >= d1.length_squared() {
+            (d0, d1)
+        } else {
+            (d1, d0)
+        };
+        // very eccentric ellipses would read too many texels, widen them instead
+        let (major_length, minor_length) = (major.length(), minor.length());
+        if minor_length * MAX_ANISOTROPY < major_length && minor_length > 0.0 {
+            minor *= major_length / (minor_length * MAX_ANISOTROPY);
+        }
+        if minor.length_squared() == 0.0 {
+            return self.bilinear(st);
+        }
+
+        // the ellipse covers a few texels of the level chosen by its minor axis
+        let level = self.level_for(minor.length());
+        let (fine, t) = (level.floor() as usize, level.fract());
+        let fine_sample = self.level(fine).ewa_level(st, major, minor);
+        if t == 0.0 {
+            return fine_sample;
+        }
+        fine_sample * (1.0 - t) + self.level(fine + 1).ewa_level(st, major, minor) * t
+    }
+
+    // Gaussian weighted average of the texels inside the ellipse with the given axes
+    fn ewa_level(&self, st: DVec2, d0: DVec2, d1: DVec2) -> DVec3 {
+        let size = DVec2::new(self.width as f64, self.height as f64);
+        let (s, t) = (st.x * size.x - 0.5, st.y * size.y - 0.5);
+        let (d0, d1) = (d0 * size, d1 * size);
+
+        // implicit ellipse a*x^2 + b*x*y + c*y^2 < 1, grown by a texel to avoid aliasing
+        let a = d0.y * d0.y + d1.y * d1.y + 1.0;
+        let b = -2.0 * (d0.x * d0.y + d1.x * d1.y);
+        let c = d0.x * d0.x + d1.x * d1.x + 1.0;
+        let scale = 1.0 / (a * c - b * b * 0.25);
+        let (a, b, c) = (a * scale, b * scale, c * scale);
+
+        // bounding box of the ellipse
+        let det = 4.0 * a * c - b * b;
+        let s_extent = 2.0 * (det * c).sqrt() / det;
+        let t_extent = 2.0 * (det * a).sqrt() / det;
+        let (s0, s1) = ((s - s_extent).ceil() as i64, (s + s_extent).floor() as i64);
+        let (t0, t1) = ((t - t_extent).ceil() as i64, (t + t_extent).floor() as i64);
+
+        const FALLOFF: f64 = 2.0;
+        let mut sum = DVec3::ZERO;
+        let mut weight_sum = 0.0;
+        for y in t0..=t1 {
+            let dt = y as f64 - t;
+            for x in s0..=s1 {
+                let ds = x as f64 - s;
+                let r2 = a * ds * ds + b * ds * dt + c * dt * dt;
+                if r2 < 1.0 {
+                    let weight = (-FALLOFF * r2).exp() - (-FALLOFF).exp();
+                    let texel = self.get(
+                        wrap(self.wrap_u, x, self.width),
+                        wrap(self.wrap_v, y, self.height),
+                    );
+                    sum += texel * weight;
+                    weight_sum += weight;
+                }
+            }
+        }
+        if weight_sum > 0.0 {
+            sum / weight_sum
+        } else {
+            self.bilinear(st)
+        }
     }
 
     pub fn rgb_buffer(&self) -> Vec<u8> {
@@ -56,15 +441,112 @@ impl Texture {
         buf
     }
 
+    /// 8 bit RGBA with straight alpha. Light added where alpha is zero, like reflections
+    /// on a shadow catcher, can't be represented and is lost.
+    pub fn rgba_buffer(&self) -> Vec<u8> {
+        self.straight_rgba()
+            .map(|channel| (channel * 255.0) as u8)
+            .collect()
+    }
+
+    /// Like `rgba_buffer`, with 16 bits per channel.
+    pub fn rgba16_buffer(&self) -> Vec<u16> {
+        self.straight_rgba()
+            .map(|channel| (channel * 65535.0) as u16)
+            .collect()
+    }
+
+    /// Writes the image with alpha, see `OutputFormat`.
+    pub fn save(&self, path: impl AsRef<Path>, format: OutputFormat) -> image::ImageResult<()> {
+        let (width, height) = (self.width, self.height);
+        match format {
+            OutputFormat::Png8 => image::save_buffer_with_format(
+                path,
+                &self.rgba_buffer(),
+                width,
+                height,
+                image::ColorType::Rgba8,
+                ImageFormat::Png,
+            ),
+            OutputFormat::Png16 => {
+                let buffer: ImageBuffer<Rgba<u16>, _> =
+                    ImageBuffer::from_raw(width, height, self.rgba16_buffer()).unwrap();
+                buffer.save_with_format(path, ImageFormat::Png)
+            }
+            OutputFormat::Exr => {
+                let data: Vec<f32> = self
+                    .buffer
+                    .iter()
+                    .zip(&self.alpha)
+                    .flat_map(|(color, alpha)| {
+                        [color.x, color.y, color.z, *alpha].map(|channel| channel as f32)
+                    })
+                    .collect();
+                let buffer: ImageBuffer<Rgba<f32>, _> =
+                    ImageBuffer::from_raw(width, height, data).unwrap();
+                buffer.save_with_format(path, ImageFormat::OpenExr)
+            }
+        }
+    }
+
+    // gamma encoded channels in [0, 1], pixel by pixel
+    fn straight_rgba(&self) -> impl Iterator<Item = f64> + '_ {
+        self.buffer
+            .iter()
+            .zip(&self.alpha)
+            .flat_map(|(color, alpha)| {
+                let straight = if *alpha > 0.0 {
+                    *color / *alpha
+                } else {
+                    DVec3::ZERO
+                };
+                let color_gamma = Self::to_gamma(straight);
+                [
+                    color_gamma.x,
+                    color_gamma.y,
+                    color_gamma.z,
+                    alpha.clamp(0.0, 1.0),
+                ]
+            })
+    }
+
     fn to_gamma(color: DVec3) -> DVec3 {
-        DVec3::new(
-            color.x.clamp(0.0, 1.0).sqrt(),
-            color.y.clamp(0.0, 1.0).sqrt(),
-            color.z.clamp(0.0, 1.0).sqrt(),
-        )
+        color.map(|c| srgb_encode(c.clamp(0.0, 1.0)))
     }
 
     fn to_linear(color: DVec3) -> DVec3 {
-        DVec3::new(color.x.powi(2), color.y.powi(2), color.z.powi(2))
+        color.map(srgb_decode)
+    }
+}
+
+// sRGB transfer function, from linear to encoded values in [0, 1]
+fn srgb_encode(c: f64) -> f64 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// inverse of `srgb_encode`
+fn srgb_decode(c: f64) -> f64 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
     }
 }
+
+// texel index for a possibly out of range one
+fn wrap(mode: WrapMode, i: i64, size: u32) -> u32 {
+    let size = size as i64;
+    let i = match mode {
+        WrapMode::Repeat => i.rem_euclid(size),
+        WrapMode::MirroredRepeat => {
+            let i = i.rem_euclid(2 * size);
+            if i < size { i } else { 2 * size - 1 - i }
+        }
+        WrapMode::ClampToEdge => i.clamp(0, size - 1),
+    };
+    i as u32
+}
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+
+use crate::hittable::{HitRecord, Hittable};
+use crate::light::{DEFAULT_LIGHT_GROUP, LightSource};
+use crate::light_bvh::LightBvh;
+use crate::ray::{Ray, RayKind};
+use crate::sampler;
+
+use glam::DVec3;
+
+/// Everything an integrator needs to know about the scene while rendering.
+pub struct World<'a> {
+    pub objects: &'a [&'a dyn Hittable],
+    // the emissive objects, for sampling light sources directly
+    pub lights: Vec<&'a dyn Hittable>,
+    pub light_sources: Vec<&'a dyn LightSource>,
+    pub background: DVec3,
+    // the light groups of the emitters, light sources and background, the default one first
+    pub light_groups: Vec<String>,
+    // whether any object is a holdout or shadow catcher
+    pub has_mattes: bool,
+    light_bvh: LightBvh,
+    // index of each emitter in `lights`, by address
+    light_indices: HashMap<*const (), usize>,
+}
+
+impl<'a> World<'a> {
+    pub fn new(
+        objects: &'a [&'a dyn Hittable],
+        light_sources: &'a [&'a dyn LightSource],
+        background: DVec3,
+    ) -> Self {
+        let lights: Vec<_> = objects
+            .iter()
+            .filter(|obj| obj.is_emissive() && obj.area() > 0.0)
+            .copied()
+            .collect();
+        let mut light_groups: Vec<String> = objects
+            .iter()
+            .filter(|obj| obj.is_emissive())
+            .map(|obj| obj.light_group())
+            .chain(light_sources.iter().map(|light| light.light_group()))
+            .filter(|group| *group != DEFAULT_LIGHT_GROUP)
+            .map(str::to_owned)
+            .collect();
+        light_groups.sort();
+        light_groups.dedup();
+        light_groups.insert(0, DEFAULT_LIGHT_GROUP.to_owned());
+
+        let light_bvh = LightBvh::new(&lights);
+        let light_indices = lights
+            .iter()
+            .enumerate()
+            .map(|(i, light)| (Self::address(*light), i))
+            .collect();
+        Self {
+            objects,
+            lights,
+            light_sources: light_sources.to_vec(),
+            background,
+            light_groups,
+            has_mattes: objects.iter().any(|obj| obj.is_matte()),
+            light_bvh,
+            light_indices,
+        }
+    }
+
+    /// Index of `light_group` in `light_groups`, for `Radiance`.
+    pub fn light_group_index(&self, light_group: &str) -> usize {
+        self.light_groups
+            .iter()
+            .position(|group| group == light_group)
+            .expect("Unable to find light group of an emitter outside the world")
+    }
+
+    /// Closest hit of `ray`, skipping the objects invisible to rays of `kind`.
+    pub fn trace(&self, ray: &Ray, kind: RayKind) -> Option<HitRecord<'a>> {
+        let hit_record = self
+            .objects
+            .iter()
+            .filter(|obj| obj.visibility().sees(kind))
+            .fold(None, |acc, obj| {
+                match (acc, obj.hit(ray)) {
+                    // pick the closest hit
+                    (None, None) => None,
+                    (Some(x), None) => Some(x),
+                    (None, Some(x)) => Some(x),
+                    (Some(x), Some(y)) => {
+                        if x.t < y.t {
+                            Some(x)
+                        } else {
+                            Some(y)
+                        }
+                    }
+                }
+            })?;
+        Some(match hit_record.differentials(ray) {
+            Some(differentials) => HitRecord {
+                duv_dx: differentials.duv_dx,
+                duv_dy: differentials.duv_dy,
+                ..hit_record
+            },
+            None => hit_record,
+        })
+    }
+
+    /// Radiance emitted from `hit_record` towards `wo`, if its emitter is linked to the
+    /// `receiver` it lights, none for the camera.
+    pub fn emitted(
+        &self,
+        hit_record: &HitRecord,
+        wo: DVec3,
+        receiver: Option<&dyn Hittable>,
+    ) -> DVec3 {
+        let material = hit_record.material;
+        if !Self::illuminates(material.light_group(), receiver) {
+            return DVec3::ZERO;
+        }
+        material.emit(hit_record, wo)
+    }
+
+    /// Whether light of `light_group` arriving at `receiver` counts by its light linking.
+    /// Light reaching the camera directly always does.
+    pub fn illuminates(light_group: &str, receiver: Option<&dyn Hittable>) -> bool {
+        receiver.is_none_or(|receiver| receiver.light_linking().includes(light_group))
+    }
+
+    pub fn visible(&self, from: DVec3, to: DVec3) -> bool {
+        let ray = Ray {
+            origin: from,
+            dir: to - from,
+            differential: None,
+        };
+        // the ray direction spans the whole segment, so t is relative to its length
+        !self.objects.iter().any(|obj| {
+            obj.visibility().shadow
+                && obj
+                    .hit(&ray)
+                    .is_some_and(|hit_record| hit_record.t < 1.0 - 0.0001)
+        })
+    }
+
+    /// Whether nothing blocks the way from `from` along the normalized `dir` for `distance`,
+    /// which may be infinite.
+    pub fn unoccluded(&self, from: DVec3, dir: DVec3, distance: f64) -> bool {
+        let ray = Ray {
+            origin: from,
+            dir,
+            differential: None,
+        };
+        !self.objects.iter().any(|obj| {
+            obj.visibility().shadow
+                && obj
+                    .hit(&ray)
+                    .is_some_and(|hit_record| hit_record.t < distance * (1.0 - 0.0001))
+        })
+    }
+
+    /// Picks an emitter uniformly and a point uniformly on it. Returns the point and its
+    /// density with respect to area.
+    pub fn sample_light(&self) -> Option<(HitRecord<'a>, f64)> {
+        if self.lights.is_empty() {
+            return None;
+        }
+        let light = self.lights[sampler::usize(self.lights.len())];
+        let hit_record = light.sample_surface()?;
+        Some((hit_record, self.light_pdf(light)))
+    }
+
+    /// Picks an emitter by how much light it likely contributes to `pos` on a surface with
+    /// `normal`, and a point uniformly on it. Returns the point and its density with respect
+    /// to area.
+    pub fn sample_emitter(
+        &self,
+        pos: DVec3,
+        normal: Option<DVec3>,
+    ) -> Option<(HitRecord<'a>, f64)> {
+        let (index, pmf) = self.light_bvh.sample(pos, normal)?;
+        let light = self.lights[index];
+        let hit_record = light.sample_surface()?;
+        Some((hit_record, pmf / light.area()))
+    }
+
+    /// Area density of `sample_emitter` picking a point on `light`.
+    pub fn emitter_pdf(&self, pos: DVec3, normal: Option<DVec3>, light: &dyn Hittable) -> f64 {
+        match self.light_indices.get(&Self::address(light)) {
+            Some(index) => self.light_bvh.pmf(pos, normal, *index) / light.area(),
+            None => 0.0,
+        }
+    }
+
+    fn address(obj: &dyn Hittable) -> *const () {
+        obj as *const dyn Hittable as *const ()
+    }
+
+    /// Area density of `sample_light` picking a point on `light`.
+    pub fn light_pdf(&self, light: &dyn Hittable) -> f64 {
+        if !light.is_emissive() {
+            return 0.0;
+        }
+        1.0 / (self.lights.len() as f64 * light.area())
+    }
+}